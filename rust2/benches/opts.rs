@@ -26,7 +26,7 @@ fn run_bf(bf: &str) {
     let bump = Bump::new();
     let ast = brainfuck::parse::parse(&bump, bf.bytes().enumerate()).unwrap();
     let hir = brainfuck::hir::optimized_hir(&bump, &ast);
-    let lir = brainfuck::lir::generate(&bump, &hir);
+    let lir = brainfuck::lir::generate(&hir);
     brainfuck::lir::interpreter::run(&lir, MockReadWrite, MockReadWrite, |_| {});
 }
 
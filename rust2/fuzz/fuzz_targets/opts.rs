@@ -0,0 +1,21 @@
+//! Checks that the optimizations in `hir::opts` don't change the behaviour of programs.
+//!
+//! `cargo fuzz run opts`
+
+#![no_main]
+
+use brainfuck::difftest::{self, Program};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: (Program, Vec<u8>)| {
+    let (program, input) = data;
+
+    if let Err(divergence) = difftest::check(&program.render(), &input) {
+        let minimized = difftest::minimize(&program, &input);
+        panic!(
+            "optimizations changed the behaviour of the program\n{divergence}\n\
+             minimized reproducer: `{}` with input {input:?}",
+            minimized.render(),
+        );
+    }
+});
@@ -0,0 +1,359 @@
+//! going back in time
+//!
+//! Every executed statement pushes an [`Undo`] entry with the position before it, the cells it
+//! wrote and how it changed the procedures of the `pbrain` dialect. To keep memory bounded, a
+//! full [`Snapshot`] of the tape is taken every [`SNAPSHOT_INTERVAL`] statements and the undo log
+//! is cleared. Going back further than the last snapshot restores an older snapshot and executes
+//! forward again from there.
+//!
+//! At most [`MAX_SNAPSHOTS`] are kept. When there are more, every other one is dropped, except for
+//! the first and the last. So it's always possible to go back to the start, but the further back,
+//! the more statements might have to be executed again to get there.
+//!
+//! Input and output are recorded in an [`IoLog`], so that executing forward again reads the same
+//! input and doesn't print the output a second time.
+
+use std::{
+    cell::RefCell,
+    io::{Read, Write},
+    rc::Rc,
+};
+
+use crate::{
+    debugger::{Debugger, PeekInput},
+    lir::{
+        interpreter::{CellWrite, MEM_SIZE},
+        Stmt,
+    },
+};
+
+const SNAPSHOT_INTERVAL: u64 = 100_000;
+/// with 32 KB per tape, about 2 MB
+const MAX_SNAPSHOTS: usize = 64;
+
+#[derive(Default)]
+pub(super) struct History {
+    snapshots: Vec<Snapshot>,
+    /// one entry for every statement executed since the last snapshot
+    undo: Vec<Undo>,
+}
+
+struct Snapshot {
+    step: u64,
+    ip: usize,
+    ptr: usize,
+    mem: Vec<u8>,
+    procedures: Vec<(u8, usize)>,
+    call_stack: Vec<usize>,
+    input_pos: usize,
+    output_pos: usize,
+}
+
+impl History {
+    /// Drops every other snapshot, but keeps the first one, and the last one, where the undo log
+    /// starts
+    fn thin_out(&mut self) {
+        let last = self.snapshots.pop();
+        let mut idx = 0;
+        self.snapshots.retain(|_| {
+            idx += 1;
+            idx % 2 == 1
+        });
+        self.snapshots.extend(last);
+    }
+}
+
+pub(super) struct Undo {
+    pub ip: usize,
+    pub ptr: usize,
+    pub writes: [Option<CellWrite>; 2],
+    pub procedures: ProcedureChange,
+}
+
+/// What a statement did to the procedures and the call stack
+pub(super) enum ProcedureChange {
+    None,
+    /// defined procedure `number`, which started at `old` before
+    Define {
+        number: u8,
+        old: Option<usize>,
+    },
+    /// pushed the ip to return to
+    Call,
+    /// popped `ip` to return to it
+    Return {
+        ip: usize,
+    },
+}
+
+impl Undo {
+    pub fn changed_cell(&self, idx: usize) -> bool {
+        self.writes
+            .iter()
+            .flatten()
+            .any(|write| write.idx == idx && write.old != write.new)
+    }
+}
+
+/// Everything the program has read and written so far
+#[derive(Default)]
+pub(super) struct IoLog {
+    input: Vec<u8>,
+    input_pos: usize,
+    output_pos: usize,
+    output_len: usize,
+}
+
+/// Reads input that has been read before from the log instead
+pub(super) struct ReplayInput<R> {
+    pub inner: R,
+    pub log: Rc<RefCell<IoLog>>,
+}
+
+impl<R: Read> Read for ReplayInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut log = self.log.borrow_mut();
+        let log = &mut *log;
+
+        if log.input_pos == log.input.len() {
+            let n = self.inner.read(buf)?;
+            log.input.extend_from_slice(&buf[..n]);
+            log.input_pos += n;
+            return Ok(n);
+        }
+
+        let recorded = &log.input[log.input_pos..];
+        let n = recorded.len().min(buf.len());
+        buf[..n].copy_from_slice(&recorded[..n]);
+        log.input_pos += n;
+        Ok(n)
+    }
+}
+
+/// Drops output that has been written before
+pub(super) struct ReplayOutput<W> {
+    pub inner: W,
+    pub log: Rc<RefCell<IoLog>>,
+}
+
+impl<W: Write> Write for ReplayOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut log = self.log.borrow_mut();
+
+        let written_before = (log.output_len - log.output_pos).min(buf.len());
+        self.inner.write_all(&buf[written_before..])?;
+
+        log.output_pos += buf.len();
+        log.output_len = log.output_len.max(log.output_pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: PeekInput> PeekInput for ReplayInput<R> {
+    fn at_end(&mut self) -> std::io::Result<bool> {
+        let log = self.log.borrow();
+        if log.input_pos < log.input.len() {
+            return Ok(false);
+        }
+        drop(log);
+        self.inner.at_end()
+    }
+}
+
+impl<W, R, P, U> Debugger<'_, '_, W, R, P, U>
+where
+    W: Write,
+    R: PeekInput,
+    P: FnMut(usize),
+    U: Write,
+{
+    /// The write of the last executed statement that hit a watchpoint
+    pub(super) fn hit_watchpoint(&self) -> Option<CellWrite> {
+        let undo = self.history.undo.last()?;
+        let stmt = self.lir.stmts()[undo.ip];
+        undo.writes.iter().flatten().copied().find(|write| {
+            self.watchpoints
+                .iter()
+                .any(|w| w.is_hit(stmt, undo.ptr, write.idx, write.old, write.new))
+        })
+    }
+
+    /// Executes a single statement and records it. Returns `false` if the program has ended.
+    pub(super) fn step_forward(&mut self) -> bool {
+        let ip = self.interpreter.ip();
+        let ptr = self.interpreter.ptr();
+        if let Stmt::End = self.lir.stmts()[ip] {
+            return false;
+        }
+
+        let last_snapshot = self.history.snapshots.last().map(|snapshot| snapshot.step);
+        if self.step.is_multiple_of(SNAPSHOT_INTERVAL) && last_snapshot != Some(self.step) {
+            self.take_snapshot();
+        }
+
+        let call_depth = self.interpreter.call_stack().len();
+        let procedures = match self.lir.stmts()[ip] {
+            Stmt::DefineProc(_) => {
+                let number = self.interpreter.cell(ptr);
+                let old = self.interpreter.procedure(number);
+                ProcedureChange::Define { number, old }
+            }
+            Stmt::Return => match self.interpreter.call_stack().last() {
+                Some(&ip) => ProcedureChange::Return { ip },
+                None => ProcedureChange::None,
+            },
+            _ => ProcedureChange::None,
+        };
+
+        self.interpreter.step();
+        self.step += 1;
+
+        // calling an undefined procedure does nothing
+        let procedures = match self.interpreter.call_stack().len() > call_depth {
+            true => ProcedureChange::Call,
+            false => procedures,
+        };
+        let writes = std::mem::take(self.interpreter.watcher_mut()).writes;
+        self.history.undo.push(Undo {
+            ip,
+            ptr,
+            writes,
+            procedures,
+        });
+
+        true
+    }
+
+    /// Goes back to right before the statement that was executed as the `target`th step
+    pub(super) fn rewind_to(&mut self, target: u64) {
+        if target < self.segment_start() {
+            let idx = self
+                .history
+                .snapshots
+                .partition_point(|snapshot| snapshot.step <= target)
+                - 1;
+            self.history.snapshots.truncate(idx + 1);
+            self.restore_snapshot(idx);
+
+            while self.step < target {
+                self.step_forward();
+            }
+        }
+
+        while self.step > target {
+            self.undo_step();
+        }
+    }
+
+    /// Goes back to right before the last executed statement for which `found` returns true.
+    /// If there is none, goes back to the start and returns `false`.
+    pub(super) fn rewind_until(&mut self, found: impl Fn(&Self, &Undo) -> bool) -> bool {
+        loop {
+            let start = self.segment_start();
+            if let Some(i) = self.history.undo.iter().rposition(|undo| found(self, undo)) {
+                self.rewind_to(start + i as u64);
+                return true;
+            }
+
+            if start == 0 {
+                self.rewind_to(0);
+                return false;
+            }
+
+            // replay the previous segment to get its undo log
+            self.history.snapshots.pop();
+            self.restore_snapshot(self.history.snapshots.len() - 1);
+            while self.step < start {
+                self.step_forward();
+            }
+        }
+    }
+
+    fn segment_start(&self) -> u64 {
+        self.history
+            .snapshots
+            .last()
+            .map_or(0, |snapshot| snapshot.step)
+    }
+
+    fn undo_step(&mut self) {
+        let undo = self.history.undo.pop().expect("undo log is empty");
+
+        for write in undo.writes.iter().flatten() {
+            self.interpreter.set_cell(write.idx, write.old);
+        }
+        match undo.procedures {
+            ProcedureChange::None => {}
+            ProcedureChange::Define { number, old } => match old {
+                Some(start) => self.interpreter.define_procedure(number, start),
+                None => self.interpreter.undefine_procedure(number),
+            },
+            ProcedureChange::Call => {
+                self.interpreter.pop_call();
+            }
+            ProcedureChange::Return { ip } => self.interpreter.push_call(ip),
+        }
+        self.interpreter.set_position(undo.ip, undo.ptr);
+        self.step -= 1;
+
+        let mut log = self.io.borrow_mut();
+        match self.lir.stmts()[undo.ip] {
+            Stmt::In => log.input_pos -= 1,
+            Stmt::Out => log.output_pos -= char::from(self.interpreter.cell(undo.ptr)).len_utf8(),
+            _ => {}
+        }
+    }
+
+    fn take_snapshot(&mut self) {
+        let log = self.io.borrow();
+        self.history.snapshots.push(Snapshot {
+            step: self.step,
+            ip: self.interpreter.ip(),
+            ptr: self.interpreter.ptr(),
+            mem: (0..MEM_SIZE)
+                .map(|idx| self.interpreter.cell(idx))
+                .collect(),
+            procedures: self.interpreter.procedures().collect(),
+            call_stack: self.interpreter.call_stack().to_vec(),
+            input_pos: log.input_pos,
+            output_pos: log.output_pos,
+        });
+        drop(log);
+        self.history.undo.clear();
+
+        if self.history.snapshots.len() > MAX_SNAPSHOTS {
+            self.history.thin_out();
+        }
+    }
+
+    fn restore_snapshot(&mut self, idx: usize) {
+        let snapshot = &self.history.snapshots[idx];
+
+        for (idx, &value) in snapshot.mem.iter().enumerate() {
+            self.interpreter.set_cell(idx, value);
+        }
+        for number in 0..=u8::MAX {
+            self.interpreter.undefine_procedure(number);
+        }
+        for &(number, start) in &snapshot.procedures {
+            self.interpreter.define_procedure(number, start);
+        }
+        while self.interpreter.pop_call().is_some() {}
+        for &ip in &snapshot.call_stack {
+            self.interpreter.push_call(ip);
+        }
+        self.interpreter.set_position(snapshot.ip, snapshot.ptr);
+        self.step = snapshot.step;
+
+        let mut log = self.io.borrow_mut();
+        log.input_pos = snapshot.input_pos;
+        log.output_pos = snapshot.output_pos;
+        drop(log);
+
+        self.history.undo.clear();
+    }
+}
@@ -0,0 +1,642 @@
+//! an interactive debugger
+//!
+//! The debugger runs the same LIR as a normal run, one statement at a time. Statements are mapped
+//! back to the source code through `Lir::debug`, so an optimized statement like `AddOffset`
+//! covers all source characters it was created from.
+//!
+//! A `#` in the source code is a breakpoint, like in many other brainfuck tools.
+//!
+//! Execution is recorded, so the debugger can also go backwards, see [`history`].
+
+mod history;
+
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    io::{BufRead, Read, Write},
+    rc::Rc,
+};
+
+use crate::{
+    debugger::history::{History, IoLog, ReplayInput, ReplayOutput},
+    lir::{
+        interpreter::{CellWrite, Interpreter, WriteRecorder, MEM_SIZE},
+        Lir, Stmt,
+    },
+    watch::Watchpoint,
+    CompileError, CompileOptions, Program,
+};
+
+const HELP: &str = "\
+commands:
+  c, continue       run until the next breakpoint
+  s, step [n]       step over n source characters
+  si, stepi [n]     step over n LIR statements
+  f, finish         run until the current loop is exited
+  rc                reverse-continue: go back to the last breakpoint
+  rs [n]            reverse-step: go back n source characters
+  rsi [n]           reverse-stepi: go back n LIR statements
+  lw, last-write <cell>
+                    go back to the last time the cell was changed
+  lv, last-visit [pos]
+                    go back to the last time execution was at <pos> or here
+  b, break <pos>    set a breakpoint at <pos>, which is an offset or line:col
+  d, delete [pos]   delete the breakpoint at <pos>, or all breakpoints
+  wa, watch <cell>[=value]
+                    stop whenever the cell is written to, or changes to the value
+  uw, unwatch [cell]
+                    delete the watchpoints on the cell, or all watchpoints
+  i, info           list all breakpoints and watchpoints
+  t, tape [radius]  show the cells around the pointer
+  w, where          show the current position
+  h, help           show this help
+  q, quit           stop debugging";
+
+const DEFAULT_TAPE_RADIUS: usize = 5;
+
+/// Runs `src` in the debugger. The program writes to `stdout`, while the debugger talks to the
+/// user through `ui`. Both the program and the debugger commands read from `stdin`, unless the
+/// program has inline input. The program stops in front of a `,` when its input has ended.
+pub fn debug<W, R, U>(
+    src: &str,
+    options: &CompileOptions,
+    stdout: W,
+    stdin: R,
+    ui: U,
+) -> Result<(), CompileError>
+where
+    W: Write,
+    R: BufRead,
+    U: Write,
+{
+    let program = Program::compile(src, options)?;
+    let lir = program.lir();
+    // the inline input is not code, so it can't contain breakpoints
+    let code = match options.dialect.inline_input {
+        true => options.language.split_inline_input(src).0,
+        false => src,
+    };
+
+    let stdin = RefCell::new(stdin);
+    let io = Rc::new(RefCell::new(IoLog::default()));
+    let stdout = ReplayOutput {
+        inner: stdout,
+        log: io.clone(),
+    };
+    let stdin_for_program = ReplayInput {
+        inner: match program.inline_input() {
+            Some(input) => ProgramInput::Inline(input),
+            None => ProgramInput::Shared(&stdin),
+        },
+        log: io.clone(),
+    };
+
+    let mut debugger = Debugger {
+        src,
+        line_starts: std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect(),
+        lir,
+        interpreter: Interpreter::new(
+            lir,
+            stdout,
+            stdin_for_program,
+            |_| {},
+            WriteRecorder::default(),
+        ),
+        step: 0,
+        history: History::default(),
+        io,
+        breakpoints: BTreeSet::new(),
+        breakpoint_ips: vec![false; lir.stmts().len()],
+        watchpoints: Vec::new(),
+        ui,
+    };
+
+    for (offset, _) in code.match_indices('#') {
+        debugger.breakpoints.insert(offset);
+    }
+    debugger.update_breakpoint_ips();
+
+    debugger.repl(&stdin).expect("failed to talk to the user");
+
+    Ok(())
+}
+
+/// The input of the program
+enum ProgramInput<'a, R> {
+    /// the inline input after the `!`
+    Inline(&'a [u8]),
+    /// the same input as the debugger
+    Shared(&'a RefCell<R>),
+}
+
+impl<R: Read> Read for ProgramInput<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Inline(input) => input.read(buf),
+            Self::Shared(input) => input.borrow_mut().read(buf),
+        }
+    }
+}
+
+/// Input that can tell whether it has ended without consuming anything
+trait PeekInput: Read {
+    /// Whether the next read gets nothing. Waits for more input if there is none yet.
+    fn at_end(&mut self) -> std::io::Result<bool>;
+}
+
+impl<R: BufRead> PeekInput for ProgramInput<'_, R> {
+    fn at_end(&mut self) -> std::io::Result<bool> {
+        match self {
+            Self::Inline(input) => Ok(input.is_empty()),
+            Self::Shared(input) => Ok(input.borrow_mut().fill_buf()?.is_empty()),
+        }
+    }
+}
+
+struct Debugger<'src, 'lir, W, R, P, U> {
+    src: &'src str,
+    line_starts: Vec<usize>,
+    lir: &'lir Lir,
+    interpreter: Interpreter<'lir, W, R, P, WriteRecorder>,
+    /// how many statements have been executed
+    step: u64,
+    history: History,
+    io: Rc<RefCell<IoLog>>,
+    /// source offsets
+    breakpoints: BTreeSet<usize>,
+    /// whether there is a breakpoint on the statement, indexed by ip
+    breakpoint_ips: Vec<bool>,
+    watchpoints: Vec<Watchpoint>,
+    ui: U,
+}
+
+enum Stop {
+    /// the condition of `run_until` was met
+    Done,
+    Breakpoint,
+    Watchpoint(CellWrite),
+    /// the next statement reads input, but there is none left
+    InputEnded,
+    InputFailed(std::io::Error),
+    Ended,
+}
+
+impl<W, R, P, U> Debugger<'_, '_, W, R, P, U>
+where
+    W: Write,
+    R: PeekInput,
+    P: FnMut(usize),
+    U: Write,
+{
+    fn repl<C: BufRead>(&mut self, commands: &RefCell<C>) -> std::io::Result<()> {
+        writeln!(self.ui, "type `help` for a list of commands")?;
+        self.print_position()?;
+
+        let mut line = String::new();
+        loop {
+            write!(self.ui, "(bf) ")?;
+            self.ui.flush()?;
+
+            line.clear();
+            if commands.borrow_mut().read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else {
+                continue;
+            };
+            let arg = words.next();
+
+            match command {
+                "c" | "continue" => {
+                    let stop = self.run_until(|_| false);
+                    self.print_stop(stop)?;
+                }
+                "s" | "step" | "si" | "stepi" => {
+                    let Some(n) = self.parse_arg(arg, 1)? else {
+                        continue;
+                    };
+                    let by_char = matches!(command, "s" | "step");
+                    let mut stop = Stop::Done;
+                    for _ in 0..n {
+                        let location = self.lir.location(self.interpreter.ip());
+                        stop = self.run_until(|this| {
+                            !by_char || this.lir.location(this.interpreter.ip()) != location
+                        });
+                        if !matches!(stop, Stop::Done) {
+                            break;
+                        }
+                    }
+                    self.print_stop(stop)?;
+                }
+                "f" | "finish" => {
+                    let stop = match self.enclosing_loop_end() {
+                        Some(end) => self.run_until(|this| this.interpreter.ip() == end),
+                        None => {
+                            writeln!(self.ui, "not in a loop, running until the end")?;
+                            self.run_until(|_| false)
+                        }
+                    };
+                    self.print_stop(stop)?;
+                }
+                "rc" => {
+                    if !self.rewind_until(|this, undo| this.breakpoint_ips[undo.ip]) {
+                        writeln!(self.ui, "reached the start of the program")?;
+                    }
+                    self.print_position()?;
+                }
+                "rs" | "rsi" => {
+                    let Some(n) = self.parse_arg(arg, 1)? else {
+                        continue;
+                    };
+                    for _ in 0..n {
+                        if command == "rsi" {
+                            self.rewind_to(self.step.saturating_sub(1));
+                        } else {
+                            let location = self.lir.location(self.interpreter.ip());
+                            self.rewind_until(|this, undo| this.lir.location(undo.ip) != location);
+                        }
+                    }
+                    self.print_position()?;
+                }
+                "lw" | "last-write" => {
+                    let cell = match arg.map(str::parse::<usize>) {
+                        Some(Ok(cell)) if cell < MEM_SIZE => cell,
+                        _ => {
+                            writeln!(self.ui, "expected a cell index")?;
+                            continue;
+                        }
+                    };
+                    if !self.rewind_until(|_, undo| undo.changed_cell(cell)) {
+                        writeln!(self.ui, "cell {cell} was never changed")?;
+                    }
+                    self.print_position()?;
+                }
+                "lv" | "last-visit" => {
+                    let ips = match arg {
+                        None => {
+                            let mut ips = vec![false; self.breakpoint_ips.len()];
+                            ips[self.interpreter.ip()] = true;
+                            ips
+                        }
+                        Some(_) => {
+                            let Some(offset) = self.parse_position(arg)? else {
+                                continue;
+                            };
+                            self.ips_at(offset)
+                        }
+                    };
+                    if !self.rewind_until(|_, undo| ips[undo.ip]) {
+                        writeln!(self.ui, "execution was never there before")?;
+                    }
+                    self.print_position()?;
+                }
+                "b" | "break" => {
+                    let Some(offset) = self.parse_position(arg)? else {
+                        continue;
+                    };
+                    self.breakpoints.insert(offset);
+                    self.update_breakpoint_ips();
+                    let (line, col) = self.line_col(offset);
+                    writeln!(self.ui, "breakpoint set at {line}:{col}")?;
+                }
+                "d" | "delete" => {
+                    if arg.is_none() {
+                        self.breakpoints.clear();
+                    } else {
+                        let Some(offset) = self.parse_position(arg)? else {
+                            continue;
+                        };
+                        if !self.breakpoints.remove(&offset) {
+                            writeln!(self.ui, "no breakpoint at offset {offset}")?;
+                        }
+                    }
+                    self.update_breakpoint_ips();
+                }
+                "wa" | "watch" => match arg.map(str::parse::<Watchpoint>) {
+                    Some(Ok(watchpoint)) => self.watchpoints.push(watchpoint),
+                    _ => writeln!(self.ui, "expected <cell> or <cell>=<value>")?,
+                },
+                "uw" | "unwatch" => match arg.map(str::parse::<usize>) {
+                    None => self.watchpoints.clear(),
+                    Some(Ok(cell)) => self.watchpoints.retain(|w| w.cell != cell),
+                    Some(Err(_)) => writeln!(self.ui, "expected a cell index")?,
+                },
+                "i" | "info" => {
+                    if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+                        writeln!(self.ui, "no breakpoints or watchpoints")?;
+                    }
+                    for &offset in &self.breakpoints {
+                        let (line, col) = self.line_col(offset);
+                        writeln!(self.ui, "breakpoint at {line}:{col} (offset {offset})")?;
+                    }
+                    for watchpoint in &self.watchpoints {
+                        match watchpoint.value {
+                            Some(value) => writeln!(
+                                self.ui,
+                                "watchpoint on cell {} reaching {value}",
+                                watchpoint.cell
+                            )?,
+                            None => writeln!(self.ui, "watchpoint on cell {}", watchpoint.cell)?,
+                        }
+                    }
+                }
+                "t" | "tape" => {
+                    let Some(radius) = self.parse_arg(arg, DEFAULT_TAPE_RADIUS)? else {
+                        continue;
+                    };
+                    self.print_tape(radius)?;
+                }
+                "w" | "where" => self.print_position()?,
+                "h" | "help" => writeln!(self.ui, "{HELP}")?,
+                "q" | "quit" => return Ok(()),
+                other => writeln!(self.ui, "unknown command `{other}`, try `help`")?,
+            }
+        }
+    }
+
+    /// Executes statements until `done` returns true in front of a statement, a breakpoint is
+    /// reached or the program ends. Always executes at least one statement, unless it needs input
+    /// that isn't there.
+    fn run_until(&mut self, mut done: impl FnMut(&Self) -> bool) -> Stop {
+        loop {
+            if let Stmt::In = self.lir.stmts()[self.interpreter.ip()] {
+                match self.interpreter.stdin_mut().at_end() {
+                    Ok(false) => {}
+                    Ok(true) => return Stop::InputEnded,
+                    Err(err) => return Stop::InputFailed(err),
+                }
+            }
+            if !self.step_forward() {
+                return Stop::Ended;
+            }
+
+            // the last statement might hit a watchpoint too
+            if let Some(write) = self.hit_watchpoint() {
+                return Stop::Watchpoint(write);
+            }
+            let ip = self.interpreter.ip();
+            if let Stmt::End = self.lir.stmts()[ip] {
+                return Stop::Ended;
+            }
+            if self.breakpoint_ips[ip] {
+                return Stop::Breakpoint;
+            }
+            if done(self) {
+                return Stop::Done;
+            }
+        }
+    }
+
+    /// The ip right after the innermost loop that contains the current statement
+    fn enclosing_loop_end(&self) -> Option<usize> {
+        let ip = self.interpreter.ip();
+        self.lir.stmts()[..=ip]
+            .iter()
+            .rev()
+            .find_map(|stmt| match *stmt {
+                Stmt::JmpIfZero(end) if end as usize > ip => Some(end as usize),
+                _ => None,
+            })
+    }
+
+    /// The source characters a statement belongs to. The loop jumps only belong to their bracket
+    /// instead of the whole loop.
+    fn update_breakpoint_ips(&mut self) {
+        self.breakpoint_ips.fill(false);
+        for &offset in &self.breakpoints {
+            for (ip, is_at) in self.ips_at(offset).into_iter().enumerate() {
+                self.breakpoint_ips[ip] |= is_at;
+            }
+        }
+    }
+
+    /// The statements that contain the offset, indexed by ip. If there are none because the
+    /// offset is a comment or was optimized away, the next statement after it is used.
+    fn ips_at(&self, offset: usize) -> Vec<bool> {
+        let stmts = self.lir.stmts();
+        // the `End` has no location and is never a breakpoint
+        let locations = (0..stmts.len() - 1)
+            .map(|ip| self.lir.location(ip))
+            .collect::<Vec<_>>();
+
+        let mut ips = vec![false; stmts.len()];
+
+        for (ip, location) in locations.iter().enumerate() {
+            ips[ip] = location.contains(&offset);
+        }
+
+        if !ips.contains(&true) {
+            let next = locations
+                .iter()
+                .map(|location| location.start)
+                .filter(|&start| start > offset)
+                .min();
+            for (ip, location) in locations.iter().enumerate() {
+                ips[ip] = Some(location.start) == next;
+            }
+        }
+
+        ips
+    }
+
+    /// 1-based line and column
+    fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        (line + 1, offset - self.line_starts[line] + 1)
+    }
+
+    fn parse_position(&mut self, arg: Option<&str>) -> std::io::Result<Option<usize>> {
+        let offset = match arg.map(|arg| arg.split_once(':')) {
+            None => None,
+            Some(None) => arg.and_then(|arg| arg.parse().ok()),
+            Some(Some((line, col))) => match (line.parse::<usize>(), col.parse::<usize>()) {
+                (Ok(line), Ok(col)) if line > 0 && col > 0 => {
+                    self.line_starts.get(line - 1).map(|start| start + col - 1)
+                }
+                _ => None,
+            },
+        };
+
+        match offset {
+            Some(offset) if offset < self.src.len() => Ok(Some(offset)),
+            _ => {
+                writeln!(self.ui, "expected an offset or line:col in the source code")?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn parse_arg(&mut self, arg: Option<&str>, default: usize) -> std::io::Result<Option<usize>> {
+        match arg.map(str::parse) {
+            None => Ok(Some(default)),
+            Some(Ok(n)) => Ok(Some(n)),
+            Some(Err(_)) => {
+                writeln!(self.ui, "expected a number")?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn print_stop(&mut self, stop: Stop) -> std::io::Result<()> {
+        match stop {
+            Stop::Done => {}
+            Stop::Breakpoint => writeln!(self.ui, "hit breakpoint")?,
+            Stop::Watchpoint(write) => writeln!(
+                self.ui,
+                "hit watchpoint: cell {} changed from {} to {}",
+                write.idx, write.old, write.new
+            )?,
+            Stop::InputEnded => {
+                writeln!(self.ui, "the program needs input, but the input has ended")?
+            }
+            Stop::InputFailed(err) => writeln!(self.ui, "failed to read input: {err}")?,
+            Stop::Ended => {
+                writeln!(self.ui, "the program has ended")?;
+                return Ok(());
+            }
+        }
+        self.print_position()
+    }
+
+    fn print_position(&mut self) -> std::io::Result<()> {
+        let ip = self.interpreter.ip();
+        let stmt = self.lir.stmts()[ip];
+        if let Stmt::End = stmt {
+            return writeln!(self.ui, "the program has ended");
+        }
+
+        let location = self.lir.location(ip);
+        let (line, col) = self.line_col(location.start);
+        let line_start = self.line_starts[line - 1];
+        let line_end = self
+            .line_starts
+            .get(line)
+            .map_or(self.src.len(), |next| next - 1);
+        let marker_len = location
+            .end
+            .min(line_end)
+            .saturating_sub(location.start)
+            .max(1);
+
+        writeln!(
+            self.ui,
+            "{line}:{col} step {} ip {ip} {stmt:?}, ptr {} = {}",
+            self.step,
+            self.interpreter.ptr(),
+            self.interpreter.cell(self.interpreter.ptr())
+        )?;
+        writeln!(self.ui, "    {}", &self.src[line_start..line_end])?;
+        writeln!(
+            self.ui,
+            "    {}{}",
+            " ".repeat(col - 1),
+            "^".repeat(marker_len)
+        )
+    }
+
+    fn print_tape(&mut self, radius: usize) -> std::io::Result<()> {
+        let ptr = self.interpreter.ptr();
+        let start = ptr.saturating_sub(radius);
+        let end = (ptr + radius + 1).min(MEM_SIZE);
+
+        let mut indices = String::new();
+        let mut values = String::new();
+        for idx in start..end {
+            let (index, value) = if idx == ptr {
+                (
+                    format!("[{idx}]"),
+                    format!("[{}]", self.interpreter.cell(idx)),
+                )
+            } else {
+                (idx.to_string(), self.interpreter.cell(idx).to_string())
+            };
+            let width = index.len().max(value.len()) + 1;
+            indices.push_str(&format!("{index:>width$}"));
+            values.push_str(&format!("{value:>width$}"));
+        }
+
+        writeln!(self.ui, "index{indices}")?;
+        writeln!(self.ui, "value{values}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CompileOptions;
+
+    fn session(src: &str, commands: &str) -> String {
+        session_in("classic", src, commands)
+    }
+
+    fn session_in(dialect: &str, src: &str, commands: &str) -> String {
+        let mut stdout = Vec::new();
+        let mut ui = Vec::new();
+        let options = CompileOptions {
+            dialect: dialect.parse().unwrap(),
+            ..CompileOptions::default()
+        };
+        super::debug(src, &options, &mut stdout, commands.as_bytes(), &mut ui).unwrap();
+        format!(
+            "{}\n--- program output ---\n{}",
+            String::from_utf8(ui).unwrap(),
+            String::from_utf8(stdout).unwrap()
+        )
+    }
+
+    #[test]
+    fn hash_breakpoint() {
+        insta::assert_snapshot!(session("++>#+++\n[-]<.", "c\ntape 2\nc\n"));
+    }
+
+    #[test]
+    fn step_and_finish() {
+        insta::assert_snapshot!(session(
+            "++[>+[>+<-]<-]",
+            "b 1:5\nc\nsi\ns 2\nfinish\nfinish\nwhere\ninfo\n"
+        ));
+    }
+
+    #[test]
+    fn time_travel() {
+        insta::assert_snapshot!(session(
+            ",.>+++[<++>-]<.",
+            "b 1:8\nc\nA\nc\nlw 0\nrc\nc\nrs 2\nrsi\nc\nc\nlv\nlv 1:2\nd\nc\nx\n"
+        ));
+    }
+
+    #[test]
+    fn watchpoints() {
+        insta::assert_snapshot!(session(
+            "+++[>++<-]>[-]",
+            "watch 1=4\nwa 0\ni\nc\nc\nuw 0\nc\nc\n"
+        ));
+    }
+
+    #[test]
+    fn procedures_and_inline_input() {
+        // procedure 65 prints the cell, it's called with both input bytes
+        insta::assert_snapshot!(session_in(
+            "input,pbrain",
+            ",(.):>,:!AA",
+            "c\nrsi 2\nwhere\nc\nrsi 100\nsi 4\nwhere\nc\n"
+        ));
+    }
+
+    #[test]
+    fn end_of_input() {
+        insta::assert_snapshot!(session(",.", "c\n"));
+        insta::assert_snapshot!(session_in("input", ",.,.!a", "c\nrsi\nc\n"));
+    }
+
+    #[test]
+    fn time_travel_across_snapshots() {
+        insta::assert_snapshot!(session(
+            "-[>-[>++<-]<-]>>.",
+            "c\nrsi 150000\nt 2\nsi 149999\nt 2\nlw 2\nt 2\nrsi 200000\nt 2\nc\n"
+        ));
+    }
+}
@@ -0,0 +1,270 @@
+//! differential testing of the optimizer
+//!
+//! Random programs are run through the interpreter twice, once compiled from the plain HIR and
+//! once from the optimized HIR. Any difference in the output, the final tape or the final pointer
+//! is a bug in `hir::opts`.
+//!
+//! The optimizations are allowed to assume that the program never moves the pointer off the start
+//! of the tape, so the generated programs never do that, except for whole laps around the tape,
+//! which must wrap around to the same cell. They are also guaranteed to terminate.
+
+use std::{
+    fmt::{Display, Formatter},
+    io::{self, Read},
+};
+
+use bumpalo::Bump;
+
+use crate::{hir, lir, lir::interpreter::MEM_SIZE, parse};
+
+/// How deep loops may be nested. Every loop runs at most 255 times, so this bounds the runtime.
+const MAX_LOOP_DEPTH: usize = 2;
+
+/// The generated programs never move the pointer beyond this cell
+const TAPE_BOUND: usize = 64;
+
+/// A brainfuck instruction with a repeat count, rendered to source by [`Program::render`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub enum Op {
+    Add(u16),
+    Sub(u16),
+    Right(u16),
+    Left(u16),
+    Out,
+    In,
+    Loop(Vec<Op>),
+    /// a move to the left that is longer than the tape, and back to the right by one, which
+    /// arrives at the same cell
+    Lap,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct Program(pub Vec<Op>);
+
+impl Program {
+    /// Renders the program as brainfuck.
+    ///
+    /// Every loop is rendered as `[body-]`, where `body` never touches the counter cells of the
+    /// enclosing loops and returns to where it started, so every loop runs at most 255 times.
+    /// Instructions that would break this are skipped.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        render(&self.0, &mut out, &mut 0, &mut Vec::new());
+        out
+    }
+
+    /// Returns smaller versions of this program, each differing from it in a single place
+    fn shrink(&self) -> Vec<Program> {
+        shrink(&self.0).into_iter().map(Program).collect()
+    }
+}
+
+fn render(ops: &[Op], out: &mut String, ptr: &mut usize, counters: &mut Vec<usize>) {
+    for op in ops {
+        let touches_counter = counters.contains(ptr);
+        match op {
+            Op::Add(n) if !touches_counter => out.push_str(&"+".repeat(usize::from(*n))),
+            Op::Sub(n) if !touches_counter => out.push_str(&"-".repeat(usize::from(*n))),
+            Op::In if !touches_counter => out.push(','),
+            Op::Right(n) => {
+                let n = usize::from(*n).min(TAPE_BOUND - 1 - *ptr);
+                *ptr += n;
+                out.push_str(&">".repeat(n));
+            }
+            Op::Left(n) => {
+                let n = usize::from(*n).min(*ptr);
+                *ptr -= n;
+                out.push_str(&"<".repeat(n));
+            }
+            Op::Out => out.push('.'),
+            // only outside of loops, where it doesn't run thousands of times
+            Op::Lap if counters.is_empty() => {
+                out.push_str(&"<".repeat(MEM_SIZE + 1));
+                out.push('>');
+            }
+            Op::Loop(body) if !touches_counter && counters.len() < MAX_LOOP_DEPTH => {
+                let start = *ptr;
+                out.push('[');
+                counters.push(start);
+                render(body, out, ptr, counters);
+                counters.pop();
+                if *ptr > start {
+                    out.push_str(&"<".repeat(*ptr - start));
+                } else {
+                    out.push_str(&">".repeat(start - *ptr));
+                }
+                *ptr = start;
+                out.push_str("-]");
+            }
+            Op::Add(_) | Op::Sub(_) | Op::In | Op::Loop(_) | Op::Lap => {}
+        }
+    }
+}
+
+fn shrink(ops: &[Op]) -> Vec<Vec<Op>> {
+    let mut candidates = Vec::new();
+
+    for (i, op) in ops.iter().enumerate() {
+        let replace_with = |replacement: &[Op]| {
+            let mut candidate = ops[..i].to_vec();
+            candidate.extend_from_slice(replacement);
+            candidate.extend_from_slice(&ops[i + 1..]);
+            candidate
+        };
+
+        candidates.push(replace_with(&[]));
+
+        match op {
+            Op::Add(n) | Op::Sub(n) | Op::Right(n) | Op::Left(n) if *n > 1 => {
+                let mut smaller = op.clone();
+                if let Op::Add(m) | Op::Sub(m) | Op::Right(m) | Op::Left(m) = &mut smaller {
+                    *m = n / 2;
+                }
+                candidates.push(replace_with(&[smaller]));
+            }
+            Op::Loop(body) => {
+                candidates.push(replace_with(body));
+                for body in shrink(body) {
+                    candidates.push(replace_with(&[Op::Loop(body)]));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    candidates
+}
+
+/// Everything observable about a finished program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub output: Vec<u8>,
+    pub ptr: usize,
+    pub mem: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub unoptimized: Outcome,
+    pub optimized: Outcome,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (a, b) = (&self.unoptimized, &self.optimized);
+        writeln!(f, "unoptimized: output {:?}, pointer {}", a.output, a.ptr)?;
+        writeln!(f, "optimized:   output {:?}, pointer {}", b.output, b.ptr)?;
+        match a.mem.iter().zip(&b.mem).position(|(a, b)| a != b) {
+            Some(i) => write!(
+                f,
+                "first differing cell: {i} ({} vs {})",
+                a.mem[i], b.mem[i]
+            ),
+            None => write!(f, "tapes are equal"),
+        }
+    }
+}
+
+/// Runs `src` unoptimized and optimized, reading `input` followed by zeroes, and compares the
+/// outcomes. `src` must terminate and stay on the tape, which [`Program::render`] guarantees.
+pub fn check(src: &str, input: &[u8]) -> Result<(), Divergence> {
+    let unoptimized = run(src, input, false);
+    let optimized = run(src, input, true);
+
+    if unoptimized == optimized {
+        Ok(())
+    } else {
+        Err(Divergence {
+            unoptimized,
+            optimized,
+        })
+    }
+}
+
+/// Shrinks a program that makes [`check`] fail down to a (locally) minimal one that still does
+pub fn minimize(program: &Program, input: &[u8]) -> Program {
+    let mut current = program.clone();
+
+    'shrink: loop {
+        for candidate in current.shrink() {
+            if check(&candidate.render(), input).is_err() {
+                current = candidate;
+                continue 'shrink;
+            }
+        }
+        return current;
+    }
+}
+
+fn run(src: &str, input: &[u8], optimize: bool) -> Outcome {
+    let alloc = Bump::new();
+    let ast = parse::parse(&alloc, src.bytes().enumerate()).expect("invalid brainfuck program");
+    let hir = if optimize {
+        hir::optimized_hir(&alloc, &ast)
+    } else {
+        hir::ast_to_ir(&alloc, &ast)
+    };
+    let lir = lir::generate(&hir);
+
+    let mut output = Vec::new();
+    let stdin = input.chain(io::repeat(0));
+    let state = lir::interpreter::run(&lir, &mut output, stdin, |_| {});
+
+    Outcome {
+        output,
+        ptr: state.ptr,
+        mem: state.mem,
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn arb_program() -> impl proptest::strategy::Strategy<Value = Program> {
+    use proptest::prelude::*;
+
+    let leaf = prop_oneof![
+        (1..300u16).prop_map(Op::Add),
+        (1..300u16).prop_map(Op::Sub),
+        (1..20u16).prop_map(Op::Right),
+        (1..20u16).prop_map(Op::Left),
+        Just(Op::Out),
+        Just(Op::In),
+        Just(Op::Lap),
+    ];
+    let op = leaf.prop_recursive(MAX_LOOP_DEPTH as u32, 64, 8, |inner| {
+        prop::collection::vec(inner, 0..8).prop_map(Op::Loop)
+    });
+
+    prop::collection::vec(op, 0..32).prop_map(Program)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{Op, Program};
+
+    #[test]
+    fn render_stays_on_tape() {
+        let program = Program(vec![
+            Op::Left(3),
+            Op::Add(2),
+            Op::Loop(vec![Op::Sub(1), Op::Right(2), Op::Add(1)]),
+        ]);
+        assert_eq!(program.render(), "++[>>+<<-]");
+    }
+
+    proptest! {
+        #[test]
+        fn optimizations_preserve_behaviour(
+            program in super::arb_program(),
+            input in prop::collection::vec(any::<u8>(), 0..16),
+        ) {
+            let src = program.render();
+            if let Err(divergence) = super::check(&src, &input) {
+                prop_assert!(false, "`{}` diverged\n{}", src, divergence);
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! the primitives of the binary file formats
+//!
+//! Numbers are LEB128 varints. Reading fails with [`UnexpectedEof`](io::ErrorKind::UnexpectedEof)
+//! if the input ends in the middle of a value, and with
+//! [`InvalidData`](io::ErrorKind::InvalidData) if a varint is too long.
+
+use std::io::{self, Read, Write};
+
+pub(crate) fn write_varint(out: &mut impl Write, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return out.write_all(&[byte]);
+        }
+        out.write_all(&[byte | 0x80])?;
+    }
+}
+
+pub(crate) fn read_varint(input: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(input)?;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint is too long",
+    ))
+}
+
+pub(crate) fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0];
+    input.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+/// Writes the length of `bytes` followed by them
+pub(crate) fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    write_varint(out, bytes.len() as u64)?;
+    out.write_all(bytes)
+}
+
+/// Reads bytes written by [`write_bytes`], if there are at most `max_len`
+pub(crate) fn read_bytes(input: &mut impl Read, max_len: usize) -> io::Result<Vec<u8>> {
+    let len = read_varint(input)?;
+    if len > max_len as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "too many bytes"));
+    }
+    // a broken length must not allocate more than there is
+    let mut bytes = Vec::new();
+    input.by_ref().take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
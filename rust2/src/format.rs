@@ -0,0 +1,322 @@
+//! formatting and minifying brainfuck source code
+//!
+//! Both work on the classic 8 commands and never change what the program does. The formatter
+//! only changes whitespace around the commands, keeping every comment after the command it
+//! followed in the source, either on the same line or on its own line like it was written.
+
+use bumpalo::Bump;
+
+use crate::parse::{self, ParseError};
+
+const INDENT: &str = "    ";
+
+fn is_command(byte: u8) -> bool {
+    b"+-<>.,[]".contains(&byte)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'src> {
+    Command(u8),
+    /// a comment on the same line as the command before it
+    TrailingComment(&'src str),
+    /// a comment on its own line
+    Comment(&'src str),
+}
+
+fn tokenize(src: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = src;
+
+    while !rest.is_empty() {
+        let comment_len = rest.bytes().position(is_command).unwrap_or(rest.len());
+        let (comment, code) = rest.split_at(comment_len);
+
+        for (i, line) in comment.split('\n').enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match i == 0 && !tokens.is_empty() {
+                true => tokens.push(Token::TrailingComment(line)),
+                false => tokens.push(Token::Comment(line)),
+            }
+        }
+
+        if let Some(&command) = code.as_bytes().first() {
+            tokens.push(Token::Command(command));
+            rest = &code[1..];
+        } else {
+            rest = code;
+        }
+    }
+
+    tokens
+}
+
+struct Formatter {
+    out: String,
+    line: String,
+    depth: usize,
+    width: usize,
+    /// the next code has to start on a new line, after a loop was opened or closed
+    must_break: bool,
+}
+
+impl Formatter {
+    fn code(&mut self, code: &str) {
+        let full = self.line.len() + code.len() > self.width && !self.line.trim().is_empty();
+        if full || self.must_break {
+            self.flush();
+        }
+        if self.line.is_empty() {
+            self.line = INDENT.repeat(self.depth);
+        }
+        self.line.push_str(code);
+    }
+
+    fn trailing_comment(&mut self, comment: &str) {
+        if self.line.is_empty() {
+            return self.comment(comment);
+        }
+        self.line.push(' ');
+        self.line.push_str(comment);
+        self.flush();
+    }
+
+    fn comment(&mut self, comment: &str) {
+        self.flush();
+        self.line = INDENT.repeat(self.depth);
+        self.line.push_str(comment);
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        if !self.line.trim().is_empty() {
+            self.out.push_str(self.line.trim_end());
+            self.out.push('\n');
+        }
+        self.line.clear();
+        self.must_break = false;
+    }
+}
+
+/// Pretty-prints `src` with one level of indentation per loop and lines of at most `width`
+/// characters, unless a comment makes them longer. Loops without nested loops or comments are
+/// kept on one line if they fit, like `[->+<]`.
+pub fn format(src: &str, width: usize) -> Result<String, ParseError> {
+    parse::parse(&Bump::new(), src.bytes().enumerate())?;
+
+    let tokens = tokenize(src);
+    let mut f = Formatter {
+        out: String::new(),
+        line: String::new(),
+        depth: 0,
+        width,
+        must_break: false,
+    };
+
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i] {
+            Token::Command(b'[') => {
+                let inline_loop = tokens[i + 1..]
+                    .iter()
+                    .position(|token| *token == Token::Command(b']'))
+                    .map(|len| &tokens[i..=i + 1 + len])
+                    .filter(|tokens| {
+                        let simple = tokens[1..]
+                            .iter()
+                            .all(|token| matches!(token, Token::Command(byte) if *byte != b'['));
+                        // it may still need to go on the next line
+                        simple && f.depth * INDENT.len() + tokens.len() <= f.width
+                    });
+
+                match inline_loop {
+                    Some(tokens) => {
+                        let code = tokens
+                            .iter()
+                            .map(|token| match token {
+                                Token::Command(byte) => *byte as char,
+                                _ => unreachable!(),
+                            })
+                            .collect::<String>();
+                        f.code(&code);
+                        i += tokens.len();
+                        continue;
+                    }
+                    None => {
+                        f.code("[");
+                        f.depth += 1;
+                        f.must_break = true;
+                    }
+                }
+            }
+            Token::Command(b']') => {
+                f.flush();
+                f.depth -= 1;
+                f.code("]");
+                f.must_break = true;
+            }
+            Token::Command(byte) => f.code(&(byte as char).to_string()),
+            Token::TrailingComment(comment) => f.trailing_comment(comment),
+            Token::Comment(comment) => f.comment(comment),
+        }
+        i += 1;
+    }
+    f.flush();
+
+    Ok(f.out)
+}
+
+/// Strips everything but the commands from `src` and removes code that provably does nothing:
+/// loops that are never entered because their cell is known to be zero, like loops at the start
+/// of the program or right after another loop, `+-` and `<>` pairs that cancel out and changes to
+/// the tape after the last input or output.
+pub fn minify(src: &str) -> Result<String, ParseError> {
+    parse::parse(&Bump::new(), src.bytes().enumerate())?;
+
+    let commands = src
+        .bytes()
+        .filter(|&byte| is_command(byte))
+        .collect::<Vec<_>>();
+    let mut out = Vec::with_capacity(commands.len());
+    // no cell has been changed yet, so all of them are zero
+    let mut untouched = true;
+
+    let mut i = 0;
+    while i < commands.len() {
+        let command = commands[i];
+
+        // the current cell is zero at the start and after a loop
+        if command == b'[' && (untouched || out.last() == Some(&b']')) {
+            i = matching_bracket(&commands, i) + 1;
+            continue;
+        }
+
+        match (out.last(), command) {
+            (Some(b'+'), b'-') | (Some(b'-'), b'+') | (Some(b'>'), b'<') | (Some(b'<'), b'>') => {
+                out.pop();
+            }
+            _ => out.push(command),
+        }
+        if matches!(command, b'+' | b'-' | b',') {
+            untouched = false;
+        }
+        i += 1;
+    }
+
+    // nobody can see the tape after the program ended
+    while let Some(b'+' | b'-' | b'>' | b'<') = out.last() {
+        out.pop();
+    }
+
+    Ok(String::from_utf8(out).unwrap())
+}
+
+/// The index of the `]` that closes the `[` at `open`
+fn matching_bracket(commands: &[u8], open: usize) -> usize {
+    let mut depth = 0;
+    for (i, &command) in commands.iter().enumerate().skip(open) {
+        match command {
+            b'[' => depth += 1,
+            b']' if depth == 1 => return i,
+            b']' => depth -= 1,
+            _ => {}
+        }
+    }
+    unreachable!("brackets are balanced")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+
+    use bumpalo::Bump;
+    use proptest::prelude::*;
+
+    use crate::{difftest, hir, lir, parse};
+
+    const COMMENTED: &str = "\
+prints a B
+++++++++ [ set up the counter
+>++++++++[>+>++<<-] nested
+>[-] clear <<-
+] done
+>>>++.<<[->+<]
+";
+
+    fn output(src: &str, input: &[u8]) -> Vec<u8> {
+        let alloc = Bump::new();
+        let ast = parse::parse(&alloc, src.bytes().enumerate()).unwrap();
+        let hir = hir::optimized_hir(&alloc, &ast);
+        let lir = lir::generate(&hir);
+        let mut stdout = Vec::new();
+        lir::interpreter::run(&lir, &mut stdout, input.chain(io::repeat(0)), |_| {});
+        stdout
+    }
+
+    fn commands(src: &str) -> String {
+        src.chars().filter(|c| "+-<>.,[]".contains(*c)).collect()
+    }
+
+    #[test]
+    fn format() {
+        insta::assert_snapshot!(super::format(COMMENTED, 80).unwrap());
+    }
+
+    #[test]
+    fn format_narrow() {
+        insta::assert_snapshot!(super::format(COMMENTED, 12).unwrap());
+    }
+
+    #[test]
+    fn format_unbalanced() {
+        assert!(super::format("[[]", 80).is_err());
+        assert!(super::minify("]").is_err());
+    }
+
+    #[test]
+    fn minify() {
+        assert_eq!(
+            super::minify(COMMENTED).unwrap(),
+            "++++++++[>++++++++[>+>++<<-]>[-]<<-]>>>++.<<[->+<]"
+        );
+        assert_eq!(
+            super::minify("[comment loop]+-+><.[-][-]+[-]>+").unwrap(),
+            "+.[-]+[-]"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn format_keeps_commands(
+            program in difftest::arb_program(),
+            comments in prop::collection::vec(
+                (any::<prop::sample::Index>(), "[a-z \n]{1,12}"),
+                0..8,
+            ),
+            width in 1..100usize,
+        ) {
+            let mut src = program.render();
+            for (idx, comment) in comments {
+                let idx = idx.index(src.len() + 1);
+                src.insert_str(idx, &comment);
+            }
+
+            let formatted = super::format(&src, width).unwrap();
+            prop_assert_eq!(commands(&formatted), commands(&src));
+            prop_assert_eq!(super::format(&formatted, width).unwrap(), formatted);
+        }
+
+        #[test]
+        fn minify_keeps_behaviour(
+            program in difftest::arb_program(),
+            input in prop::collection::vec(any::<u8>(), 0..16),
+        ) {
+            let src = program.render();
+            let minified = super::minify(&src).unwrap();
+            prop_assert!(minified.len() <= src.len());
+            prop_assert_eq!(output(&minified, &input), output(&src, &input));
+        }
+    }
+}
@@ -4,11 +4,14 @@ use bumpalo::Bump;
 use dbg_pls::DebugPls;
 
 use crate::{
-    parse::{Ast, Instr, Span},
+    lir::interpreter::MEM_SIZE,
+    parse::{Ast, Instr, Repeatable, Span},
     BumpVec,
 };
 
 pub mod opts;
+pub mod pseudo;
+pub mod rle;
 
 #[derive(Clone)]
 pub struct Hir<'hir> {
@@ -69,10 +72,25 @@ pub enum StmtKind<'hir> {
     Out,
     In,
     SetN(u8),
+    DebugDump,
+    /// Defines the procedure with the number of the current cell
+    Procedure(Hir<'hir>),
+    /// Calls the procedure with the number of the current cell
+    Call,
 }
 
-fn ast_to_ir<'hir>(alloc: &'hir Bump, ast: &Ast<'_>) -> Hir<'hir> {
-    let mut stmts = Vec::new_in(alloc);
+impl<'hir> StmtKind<'hir> {
+    /// The statements nested in loops and procedures
+    pub fn body_mut(&mut self) -> Option<&mut Hir<'hir>> {
+        match self {
+            StmtKind::Loop(body) | StmtKind::Procedure(body) => Some(body),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn ast_to_ir<'hir>(alloc: &'hir Bump, ast: &Ast<'_>) -> Hir<'hir> {
+    let mut stmts = BumpVec::new_in(alloc);
 
     let stmts_iter = ast.iter().map(|(instr, span)| {
         let kind = match instr {
@@ -86,6 +104,15 @@ fn ast_to_ir<'hir>(alloc: &'hir Bump, ast: &Ast<'_>) -> Hir<'hir> {
                 let ir_body = ast_to_ir(alloc, body);
                 StmtKind::Loop(ir_body)
             }
+            Instr::DebugDump => StmtKind::DebugDump,
+            Instr::Procedure(body) => StmtKind::Procedure(ast_to_ir(alloc, body)),
+            Instr::Call => StmtKind::Call,
+            // the cells wrap around, so `% 256` doesn't change what it does
+            Instr::Repeat(Repeatable::Add, n) => StmtKind::Add(0, (n % 256) as u8),
+            Instr::Repeat(Repeatable::Sub, n) => StmtKind::Sub(0, (n % 256) as u8),
+            // and so does the pointer, which keeps every move shorter than the tape
+            Instr::Repeat(Repeatable::Right, n) => StmtKind::Right(*n as usize % MEM_SIZE),
+            Instr::Repeat(Repeatable::Left, n) => StmtKind::Left(*n as usize % MEM_SIZE),
         };
         Stmt::new(kind, *span)
     });
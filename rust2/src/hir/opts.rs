@@ -5,6 +5,7 @@ use tracing::trace;
 
 use crate::{
     hir::{Hir, Stmt, StmtKind},
+    lir::interpreter::MEM_SIZE,
     BumpVec,
 };
 
@@ -19,71 +20,89 @@ pub fn optimize<'hir>(alloc: &'hir Bump, hir: &mut Hir<'hir>) {
     // pass_cancel_left_right_add_sub(hir);
 }
 
-/// pass that replaces things like `Sub(1) Sub(1)` with `Sub(2)`
-// TODO: This pass is really slow, speed it up please
-#[tracing::instrument(skip(alloc, ir_param))]
-fn pass_group<'hir>(alloc: &'hir Bump, ir_param: &mut Hir<'hir>) {
-    let empty_ir = Hir {
-        stmts: Vec::new_in(alloc),
-    };
+/// pass that replaces things like `Sub(1) Sub(1)` with `Sub(2)` and `Right(2) Left(1)` with
+/// `Right(1)`
+///
+/// Runs of any length are merged, with `Add`/`Sub` wrapping around like the cells do. Runs that
+/// cancel each other out completely are removed.
+#[tracing::instrument(skip(alloc, ir))]
+fn pass_group<'hir>(alloc: &'hir Bump, ir: &mut Hir<'hir>) {
+    let old_stmts = std::mem::replace(&mut ir.stmts, BumpVec::new_in(alloc));
+    let mut stmts = BumpVec::with_capacity_in(old_stmts.len(), alloc);
 
-    let ir = std::mem::replace(ir_param, empty_ir);
-
-    let new_stmts = Vec::new_in(alloc);
-    let stmts =
-        ir.stmts
-            .into_iter()
-            .fold(new_stmts, |mut stmts: BumpVec<'hir, Stmt<'hir>>, next| {
-                let Some(old) = stmts.last_mut() else {
-                if let StmtKind::Loop(mut body) = next.kind {
-                    pass_group(alloc, &mut body);
-                    stmts.push(Stmt::new(
-                         StmtKind::Loop(body),
-                        next.span,
-                    ));
-                } else {
-                    stmts.push(next);
-                }
-                return stmts;
-            };
+    for mut next in old_stmts {
+        if let Some(body) = next.kind.body_mut() {
+            pass_group(alloc, body);
+        }
 
-                match (&mut old.kind, next.kind) {
-                    (StmtKind::Add(offset_a, a), StmtKind::Add(offset_b, b))
-                        if *a < 255 && *offset_a == offset_b =>
-                    {
-                        old.span = old.span.merge(next.span);
-                        *a += b;
-                    }
-                    (StmtKind::Sub(offset_a, a), StmtKind::Sub(offset_b, b))
-                        if *a < 255 && *offset_a == offset_b =>
-                    {
-                        old.span = old.span.merge(next.span);
-                        *a += b;
-                    }
-                    (StmtKind::Right(a), StmtKind::Right(b)) if *a < 255 => {
-                        old.span = old.span.merge(next.span);
-                        *a += b;
-                    }
-                    (StmtKind::Left(a), StmtKind::Left(b)) if *a < 255 => {
-                        old.span = old.span.merge(next.span);
-                        *a += b;
-                    }
-                    (_, StmtKind::Loop(mut body)) => {
-                        pass_group(alloc, &mut body);
-                        stmts.push(Stmt {
-                            span: next.span,
-                            kind: StmtKind::Loop(body),
-                        });
-                    }
-                    (_, kind) => {
-                        stmts.push(Stmt::new(kind, next.span));
-                    }
-                }
+        let Some(last) = stmts.last_mut() else {
+            stmts.push(next);
+            continue;
+        };
 
-                stmts
-            });
+        match group(last.kind(), next.kind()) {
+            Group::None => stmts.push(next),
+            Group::Merge(kind) => {
+                last.kind = kind;
+                last.span = last.span.merge(next.span);
+            }
+            Group::Cancel => {
+                // the statement before `last` is now next to the upcoming one and may merge with it
+                stmts.pop();
+            }
+        }
+    }
 
-    *ir_param = Hir { stmts };
+    ir.stmts = stmts;
+}
+
+enum Group<'hir> {
+    None,
+    Merge(StmtKind<'hir>),
+    Cancel,
+}
+
+fn group<'hir>(a: &StmtKind<'hir>, b: &StmtKind<'hir>) -> Group<'hir> {
+    fn cell_delta(kind: &StmtKind<'_>) -> Option<(i32, i32)> {
+        match kind {
+            StmtKind::Add(offset, n) => Some((*offset, i32::from(*n))),
+            StmtKind::Sub(offset, n) => Some((*offset, -i32::from(*n))),
+            _ => None,
+        }
+    }
+
+    fn ptr_delta(kind: &StmtKind<'_>) -> Option<isize> {
+        match kind {
+            StmtKind::Right(n) => Some(isize::try_from(*n).unwrap()),
+            StmtKind::Left(n) => Some(-isize::try_from(*n).unwrap()),
+            _ => None,
+        }
+    }
+
+    if let (Some((offset_a, a)), Some((offset_b, b))) = (cell_delta(a), cell_delta(b)) {
+        if offset_a != offset_b {
+            return Group::None;
+        }
+        let sum = a + b;
+        // `rem_euclid` is always in 0..256, so the casts can't truncate
+        return match (sum.rem_euclid(256) as u8, sum.cmp(&0)) {
+            (0, _) => Group::Cancel,
+            (n, Ordering::Greater) => Group::Merge(StmtKind::Add(offset_a, n)),
+            (_, _) => Group::Merge(StmtKind::Sub(offset_a, (-sum).rem_euclid(256) as u8)),
+        };
+    }
+
+    if let (Some(a), Some(b)) = (ptr_delta(a), ptr_delta(b)) {
+        // the pointer wraps around the tape, so whole laps around it cancel out
+        let sum = (a + b) % MEM_SIZE as isize;
+        return match sum.cmp(&0) {
+            Ordering::Equal => Group::Cancel,
+            Ordering::Greater => Group::Merge(StmtKind::Right(sum.unsigned_abs())),
+            Ordering::Less => Group::Merge(StmtKind::Left(sum.unsigned_abs())),
+        };
+    }
+
+    Group::None
 }
 
 /// pass that replaces `Loop([Sub(_)])` to `SetNull`
@@ -106,10 +125,13 @@ fn pass_find_set_null_inner(ir: &mut Hir<'_>) {
             {
                 trace!(?span, "Replacing Statement with SetNull");
                 *stmt = Stmt::new(StmtKind::SetN(0), *span);
-            } else {
-                pass_find_set_null_inner(body);
+                continue;
             }
         }
+
+        if let Some(body) = stmt.kind.body_mut() {
+            pass_find_set_null_inner(body);
+        }
     }
 }
 
@@ -177,19 +199,22 @@ fn pass_add_sub_offset(ir: &mut Hir<'_>) {
     pass_add_sub_offset_inner(ir)
 }
 fn pass_add_sub_offset_inner(ir: &mut Hir<'_>) {
+    // moves are always shorter than the tape, see `ast_to_ir` and `group`
+    let offset = |distance: usize| (distance % MEM_SIZE) as i32;
+
     window_pass(ir, pass_add_sub_offset_inner, |[a, b, c]| {
         match (a.kind(), b.kind(), c.kind()) {
             (StmtKind::Right(r), StmtKind::Add(0, n), StmtKind::Left(l)) if r == l => {
-                WindowPassAction::Merge(StmtKind::Add(i32::try_from(*r).unwrap(), *n))
+                WindowPassAction::Merge(StmtKind::Add(offset(*r), *n))
             }
             (StmtKind::Left(l), StmtKind::Add(0, n), StmtKind::Right(r)) if r == l => {
-                WindowPassAction::Merge(StmtKind::Add(-i32::try_from(*r).unwrap(), *n))
+                WindowPassAction::Merge(StmtKind::Add(-offset(*r), *n))
             }
             (StmtKind::Right(r), StmtKind::Sub(0, n), StmtKind::Left(l)) if r == l => {
-                WindowPassAction::Merge(StmtKind::Sub(i32::try_from(*r).unwrap(), *n))
+                WindowPassAction::Merge(StmtKind::Sub(offset(*r), *n))
             }
             (StmtKind::Left(l), StmtKind::Sub(0, n), StmtKind::Right(r)) if r == l => {
-                WindowPassAction::Merge(StmtKind::Sub(-i32::try_from(*r).unwrap(), *n))
+                WindowPassAction::Merge(StmtKind::Sub(-offset(*r), *n))
             }
             _ => WindowPassAction::None,
         }
@@ -226,26 +251,31 @@ fn pass_move_add_to_inner(ir: &mut Hir<'_>) {
             {
                 trace!(?span, ?offset, "Replacing Statement with MoveAddTo");
                 *stmt = Stmt::new(StmtKind::MoveAddTo { offset: *offset }, *span);
-            } else {
-                pass_move_add_to_inner(body);
+                continue;
             }
         }
+
+        if let Some(body) = stmt.kind.body_mut() {
+            pass_move_add_to_inner(body);
+        }
     }
 }
 
+// disabled in `optimize` for now, but kept to be enabled again
+#[allow(dead_code)]
 #[tracing::instrument(skip(ir))]
 fn pass_unroll_loops(ir: &mut Hir<'_>) {
     let alloc = Bump::new();
     pass_unroll_loops_inner(&alloc, ir);
 }
 
+#[allow(dead_code)]
 fn pass_unroll_loops_inner(alloc: &Bump, ir: &mut Hir<'_>) {
     window_pass(ir, pass_unroll_loops, |[a, b]| {
         if let (StmtKind::SetN(n), StmtKind::Loop(body)) = (a.kind(), b.kind()) {
             let mut stmts_vec = BumpVec::new_in(alloc);
 
-            let stmts = std::iter::repeat(body.stmts.iter())
-                .take(usize::from(*n))
+            let stmts = std::iter::repeat_n(body.stmts.iter(), usize::from(*n))
                 .flatten()
                 .cloned();
             stmts_vec.extend(stmts);
@@ -260,12 +290,17 @@ fn pass_unroll_loops_inner(alloc: &Bump, ir: &mut Hir<'_>) {
 enum WindowPassAction<'hir, 'pass> {
     None,
     Merge(StmtKind<'hir>),
+    /// only used by the disabled `pass_unroll_loops`
+    #[allow(dead_code)]
     MergeMany(BumpVec<'pass, Stmt<'hir>>),
     RemoveAll,
 }
 
-fn window_pass<'hir, 'pass, P, F, const N: usize>(ir: &mut Hir<'hir>, pass_recur: P, action: F)
-where
+fn window_pass<'hir: 'pass, 'pass, P, F, const N: usize>(
+    ir: &mut Hir<'hir>,
+    pass_recur: P,
+    action: F,
+) where
     P: Fn(&mut Hir<'hir>),
     F: Fn([&Stmt<'hir>; N]) -> WindowPassAction<'hir, 'pass>,
 {
@@ -274,8 +309,7 @@ where
     let stmts = &mut ir.stmts;
     let mut i = 0;
     while i < stmts.len() {
-        let a = &mut stmts[i];
-        if let StmtKind::Loop(body) = &mut a.kind {
+        if let Some(body) = stmts[i].kind.body_mut() {
             pass_recur(body);
         }
 
@@ -319,3 +353,67 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use bumpalo::Bump;
+    use proptest::prelude::*;
+
+    use crate::{
+        hir::{ast_to_ir, Hir},
+        lir, parse,
+    };
+
+    fn grouped<'hir>(alloc: &'hir Bump, src: &str) -> Hir<'hir> {
+        let ast = parse::parse(alloc, src.bytes().enumerate()).unwrap();
+        let mut hir = ast_to_ir(alloc, &ast);
+        super::pass_group(alloc, &mut hir);
+        hir
+    }
+
+    fn run(hir: &Hir<'_>, input: &[u8]) -> Vec<u8> {
+        let lir = lir::generate(hir);
+        let mut stdout = Vec::new();
+        let stdin = input.chain(std::io::repeat(0));
+        lir::interpreter::run(&lir, &mut stdout, stdin, |_| {});
+        stdout
+    }
+
+    #[test]
+    fn group_long_runs() {
+        let alloc = Bump::new();
+        let src = format!("{}.{}.{}", "+".repeat(300), "-".repeat(44), ">".repeat(300));
+        insta::assert_debug_snapshot!(grouped(&alloc, &src));
+    }
+
+    #[test]
+    fn group_mixed_runs() {
+        let alloc = Bump::new();
+        insta::assert_debug_snapshot!(grouped(&alloc, "++-.--+.>><.<<>.+-.><.+>-<+"));
+    }
+
+    #[test]
+    fn group_cap_overflow() {
+        let alloc = Bump::new();
+        let src = format!("{}+>>", "+".repeat(255));
+        insta::assert_debug_snapshot!(grouped(&alloc, &src));
+    }
+
+    proptest! {
+        #[test]
+        fn group_preserves_output(
+            program in crate::difftest::arb_program(),
+            input in prop::collection::vec(any::<u8>(), 0..16),
+        ) {
+            let src = program.render();
+
+            let alloc = Bump::new();
+            let ast = parse::parse(&alloc, src.bytes().enumerate()).unwrap();
+            let unoptimized = ast_to_ir(&alloc, &ast);
+
+            prop_assert_eq!(run(&unoptimized, &input), run(&grouped(&alloc, &src), &input));
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! renders HIR as structured pseudo-code
+//!
+//! The tape is `c` and the pointer is `p`. Pointer moves in straight-line code are folded into
+//! the offsets of the following statements, like `c[p+3] += 2`, and only written out as `p += 3`
+//! before a loop or at the end of a block. Loops that only add multiples of their counter to
+//! other cells become multiplications, and loops that decrement their counter once per iteration
+//! without otherwise touching it become `for` loops.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::hir::{Hir, Stmt, StmtKind};
+
+pub fn render(hir: &Hir<'_>) -> String {
+    let mut printer = Printer {
+        out: String::new(),
+        depth: 0,
+        offset: 0,
+    };
+    printer.block(&hir.stmts, false);
+    printer.out
+}
+
+struct Printer {
+    out: String,
+    depth: usize,
+    /// how far the pointer has been moved since it was last written out
+    offset: i64,
+}
+
+impl Printer {
+    /// Renders `stmts`. With `counted`, the statements are the body of a `for` loop, and the
+    /// decrements of its counter are left out.
+    fn block(&mut self, stmts: &[Stmt<'_>], counted: bool) {
+        // where the pointer is relative to the start of the block, which is where the counter is
+        let mut position = 0i64;
+
+        for stmt in stmts {
+            match stmt.kind() {
+                StmtKind::Add(offset, _) | StmtKind::Sub(offset, _)
+                    if counted && position + i64::from(*offset) == 0 => {}
+                StmtKind::Add(offset, n) => {
+                    let cell = self.cell(i64::from(*offset));
+                    self.line(format_args!("{cell} += {n};"));
+                }
+                StmtKind::Sub(offset, n) => {
+                    let cell = self.cell(i64::from(*offset));
+                    self.line(format_args!("{cell} -= {n};"));
+                }
+                StmtKind::MoveAddTo { offset } => {
+                    let (from, to) = (self.cell(0), self.cell(i64::from(*offset)));
+                    self.line(format_args!("{to} += {from};"));
+                    self.line(format_args!("{from} = 0;"));
+                }
+                StmtKind::Right(n) => {
+                    position += *n as i64;
+                    self.offset += *n as i64;
+                }
+                StmtKind::Left(n) => {
+                    position -= *n as i64;
+                    self.offset -= *n as i64;
+                }
+                StmtKind::Loop(body) => self.loop_(body),
+                StmtKind::Out => {
+                    let cell = self.cell(0);
+                    self.line(format_args!("out({cell});"));
+                }
+                StmtKind::In => {
+                    let cell = self.cell(0);
+                    self.line(format_args!("{cell} = in();"));
+                }
+                StmtKind::SetN(n) => {
+                    let cell = self.cell(0);
+                    self.line(format_args!("{cell} = {n};"));
+                }
+                StmtKind::DebugDump => {
+                    self.move_pointer();
+                    self.line(format_args!("dump();"));
+                }
+                StmtKind::Procedure(body) => {
+                    self.move_pointer();
+                    self.line(format_args!("proc[c[p]] = {{"));
+                    self.nested(&body.stmts, false);
+                    self.line(format_args!("}}"));
+                }
+                StmtKind::Call => {
+                    self.move_pointer();
+                    self.line(format_args!("proc[c[p]]();"));
+                }
+            }
+        }
+        self.move_pointer();
+    }
+
+    fn loop_(&mut self, body: &Hir<'_>) {
+        let usage = Usage::of(&body.stmts);
+        let counted = usage
+            .as_ref()
+            .filter(|usage| !usage.counter_read && usage.counter_step == -1);
+
+        match counted {
+            Some(usage) if usage.only_adds => {
+                let counter = self.cell(0);
+                for (&offset, &factor) in &usage.adds {
+                    let cell = self.cell(offset);
+                    match factor {
+                        1 => self.line(format_args!("{cell} += {counter};")),
+                        255 => self.line(format_args!("{cell} -= {counter};")),
+                        2..=128 => self.line(format_args!("{cell} += {factor} * {counter};")),
+                        _ => {
+                            let factor = 256 - u16::from(factor);
+                            self.line(format_args!("{cell} -= {factor} * {counter};"));
+                        }
+                    }
+                }
+                self.line(format_args!("{counter} = 0;"));
+            }
+            Some(_) => {
+                self.move_pointer();
+                self.line(format_args!("for _ in 0..c[p] {{"));
+                self.nested(&body.stmts, true);
+                self.line(format_args!("}}"));
+                self.line(format_args!("c[p] = 0;"));
+            }
+            None => {
+                self.move_pointer();
+                self.line(format_args!("while c[p] {{"));
+                self.nested(&body.stmts, false);
+                self.line(format_args!("}}"));
+            }
+        }
+    }
+
+    fn nested(&mut self, stmts: &[Stmt<'_>], counted: bool) {
+        self.depth += 1;
+        self.block(stmts, counted);
+        self.depth -= 1;
+    }
+
+    /// Writes out the pointer moves that were folded into the offsets
+    fn move_pointer(&mut self) {
+        match self.offset {
+            0 => {}
+            offset @ 1.. => self.line(format_args!("p += {offset};")),
+            offset => self.line(format_args!("p -= {};", -offset)),
+        }
+        self.offset = 0;
+    }
+
+    /// The cell at `offset` from the current position
+    fn cell(&self, offset: i64) -> String {
+        match self.offset + offset {
+            0 => "c[p]".to_owned(),
+            offset @ 1.. => format!("c[p+{offset}]"),
+            offset => format!("c[p{offset}]"),
+        }
+    }
+
+    fn line(&mut self, line: std::fmt::Arguments<'_>) {
+        for _ in 0..self.depth {
+            self.out.push_str("    ");
+        }
+        self.out.write_fmt(line).unwrap();
+        self.out.push('\n');
+    }
+}
+
+/// How a loop body uses the tape, relative to the cell of the loop counter
+struct Usage {
+    /// what the body adds to the counter, between -128 and 127
+    counter_step: i16,
+    /// whether the counter is used in any other way
+    counter_read: bool,
+    /// whether the body only adds constants to cells
+    only_adds: bool,
+    /// the constants added to every other cell
+    adds: BTreeMap<i64, u8>,
+}
+
+impl Usage {
+    /// `None` if the body doesn't always return to the counter, or calls procedures that might
+    /// do anything
+    fn of(stmts: &[Stmt<'_>]) -> Option<Self> {
+        let mut usage = Usage {
+            counter_step: 0,
+            counter_read: false,
+            only_adds: true,
+            adds: BTreeMap::new(),
+        };
+        let mut step = 0u8;
+        let mut offset = 0i64;
+
+        for stmt in stmts {
+            match stmt.kind() {
+                StmtKind::Add(add_offset, n) | StmtKind::Sub(add_offset, n) => {
+                    let n = match stmt.kind() {
+                        StmtKind::Add(..) => *n,
+                        _ => 0u8.wrapping_sub(*n),
+                    };
+                    match offset + i64::from(*add_offset) {
+                        0 => step = step.wrapping_add(n),
+                        cell => {
+                            let add = usage.adds.entry(cell).or_insert(0);
+                            *add = add.wrapping_add(n);
+                        }
+                    }
+                }
+                StmtKind::MoveAddTo { offset: to } => {
+                    usage.only_adds = false;
+                    usage.counter_read |= offset == 0 || offset + i64::from(*to) == 0;
+                }
+                StmtKind::Right(n) => offset += *n as i64,
+                StmtKind::Left(n) => offset -= *n as i64,
+                StmtKind::Loop(body) => {
+                    usage.only_adds = false;
+                    usage.counter_read |= offset == 0 || touches(&body.stmts, -offset)?;
+                }
+                StmtKind::Out | StmtKind::In | StmtKind::SetN(_) => {
+                    usage.only_adds = false;
+                    usage.counter_read |= offset == 0;
+                }
+                StmtKind::DebugDump => {
+                    usage.only_adds = false;
+                    usage.counter_read = true;
+                }
+                StmtKind::Procedure(_) | StmtKind::Call => return None,
+            }
+        }
+
+        usage.counter_step = i16::from(step as i8);
+        usage.adds.retain(|_, add| *add != 0);
+        if offset == 0 {
+            Some(usage)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether `stmts` touch the cell at `cell`, relative to where they start. `None` if they don't
+/// return to where they started, so it can't be known.
+fn touches(stmts: &[Stmt<'_>], cell: i64) -> Option<bool> {
+    let mut offset = 0i64;
+    let mut touched = false;
+
+    for stmt in stmts {
+        match stmt.kind() {
+            StmtKind::Add(add_offset, _) | StmtKind::Sub(add_offset, _) => {
+                touched |= offset + i64::from(*add_offset) == cell;
+            }
+            StmtKind::MoveAddTo { offset: to } => {
+                touched |= offset == cell || offset + i64::from(*to) == cell;
+            }
+            StmtKind::Right(n) => offset += *n as i64,
+            StmtKind::Left(n) => offset -= *n as i64,
+            StmtKind::Loop(body) => {
+                touched |= offset == cell || touches(&body.stmts, cell - offset)?;
+            }
+            StmtKind::Out | StmtKind::In | StmtKind::SetN(_) => touched |= offset == cell,
+            StmtKind::DebugDump => touched = true,
+            StmtKind::Procedure(_) | StmtKind::Call => return None,
+        }
+    }
+
+    if offset == 0 {
+        Some(touched)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use crate::{hir, parse};
+
+    fn render(src: &str) -> String {
+        let alloc = Bump::new();
+        let ast = parse::parse(&alloc, src.bytes().enumerate()).unwrap();
+        let hir = hir::optimized_hir(&alloc, &ast);
+        super::render(&hir)
+    }
+
+    #[test]
+    fn loops() {
+        // a multiplication, a counted loop with output, a scan and a counted loop with a nested
+        // loop that reads the counter
+        insta::assert_snapshot!(render(">+++[>++>---<<-]>>.<<+++[>>.<<->+<]>[>],[-[>+<-]<]"));
+    }
+
+    #[test]
+    fn fizzbuzz() {
+        insta::assert_snapshot!(render(include_str!("../../benches/fizzbuzz.bf")));
+    }
+}
@@ -0,0 +1,120 @@
+//! writes HIR back out as brainfuck, with the repeat counts of [`Dialect::rle`]
+//!
+//! [`Dialect::rle`]: crate::parse::Dialect::rle
+
+use std::fmt::Write;
+
+use crate::hir::{Hir, Stmt, StmtKind};
+
+pub fn emit(hir: &Hir<'_>) -> String {
+    let mut out = String::new();
+    emit_stmts(&mut out, &hir.stmts);
+    out
+}
+
+fn emit_stmts(out: &mut String, stmts: &[Stmt<'_>]) {
+    for stmt in stmts {
+        match stmt.kind() {
+            StmtKind::Add(offset, n) => {
+                at_offset(out, *offset, |out| repeat(out, '+', usize::from(*n)))
+            }
+            StmtKind::Sub(offset, n) => {
+                at_offset(out, *offset, |out| repeat(out, '-', usize::from(*n)))
+            }
+            StmtKind::MoveAddTo { offset } => {
+                out.push_str("[-");
+                at_offset(out, *offset, |out| out.push('+'));
+                out.push(']');
+            }
+            StmtKind::Right(n) => repeat(out, '>', *n),
+            StmtKind::Left(n) => repeat(out, '<', *n),
+            StmtKind::Loop(body) => {
+                out.push('[');
+                emit_stmts(out, &body.stmts);
+                out.push(']');
+            }
+            StmtKind::Out => out.push('.'),
+            StmtKind::In => out.push(','),
+            StmtKind::SetN(n) => {
+                out.push_str("[-]");
+                repeat(out, '+', usize::from(*n));
+            }
+            StmtKind::DebugDump => out.push('#'),
+            StmtKind::Procedure(body) => {
+                out.push('(');
+                emit_stmts(out, &body.stmts);
+                out.push(')');
+            }
+            StmtKind::Call => out.push(':'),
+        }
+    }
+}
+
+/// Moves to the cell at `offset`, emits `f` there and moves back
+fn at_offset(out: &mut String, offset: i32, f: impl FnOnce(&mut String)) {
+    let (there, back) = if offset < 0 { ('<', '>') } else { ('>', '<') };
+    let distance = offset.unsigned_abs() as usize;
+    repeat(out, there, distance);
+    f(out);
+    repeat(out, back, distance);
+}
+
+fn repeat(out: &mut String, char: char, count: usize) {
+    match count {
+        0 => {}
+        1 => out.push(char),
+        count => write!(out, "{char}{count}").unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+    use proptest::prelude::*;
+
+    use crate::{hir, lir, parse};
+
+    fn emit(src: &str) -> String {
+        let alloc = Bump::new();
+        let ast = parse::parse(&alloc, src.bytes().enumerate()).unwrap();
+        let hir = hir::optimized_hir(&alloc, &ast);
+        super::emit(&hir)
+    }
+
+    fn run(src: &str, dialect: &str) -> Vec<u8> {
+        let alloc = Bump::new();
+        let ast = parse::parse_dialect(&alloc, src.bytes().enumerate(), dialect.parse().unwrap())
+            .unwrap();
+        let hir = hir::optimized_hir(&alloc, &ast);
+        let lir = lir::generate(&hir);
+        let mut stdout = Vec::new();
+        lir::interpreter::run(&lir, &mut stdout, std::io::repeat(0), |_| {});
+        stdout
+    }
+
+    #[test]
+    fn runs_and_offsets() {
+        insta::assert_snapshot!(emit(&format!(
+            "{}.>>>>+<<<<[->>+<<]{}[-]+++.",
+            "+".repeat(200),
+            ">".repeat(17)
+        )));
+    }
+
+    #[test]
+    fn huge_counts() {
+        assert_eq!(run(">3000000000+<3000000000.", "rle"), [1]);
+        assert_eq!(run(">2000000000>2000000000>2000000000.", "rle"), [0]);
+        assert_eq!(run("+<64000.<100000.", "rle"), [1, 0]);
+    }
+
+    proptest! {
+        #[test]
+        fn emitted_rle_behaves_the_same(program in crate::difftest::arb_program()) {
+            let src = program.render();
+            let rle = emit(&src);
+
+            prop_assert_eq!(run(&src, "classic"), run(&rle, "rle"));
+        }
+    }
+}
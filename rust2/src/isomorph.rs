@@ -0,0 +1,317 @@
+//! front-ends for languages that are brainfuck with other tokens, like Ook! and Blub
+//!
+//! Every token stands for one brainfuck command, everything else is a comment. The tokens are
+//! parsed into the same [`Ast`] as brainfuck, with spans pointing at the tokens.
+//!
+//! Custom token tables are TOML files that map the commands to their token:
+//!
+//! ```toml
+//! "+" = "plus"
+//! "-" = "minus"
+//! "[" = "while"
+//! "]" = "end"
+//! ```
+//!
+//! Tokens may consist of multiple words, which can be separated by any whitespace. Tokens only
+//! match whole words, so `plus` in `surplus` is a comment.
+
+use std::{fs, path::Path, str::FromStr};
+
+use bumpalo::Bump;
+
+use crate::parse::{self, Ast, Dialect, ParseError, Span};
+
+/// The language of the source code
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Language {
+    #[default]
+    Brainfuck,
+    Tokens(TokenTable),
+}
+
+impl FromStr for Language {
+    type Err = String;
+
+    /// `brainfuck`, `ook`, `blub`, or the path to a TOML token table
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "brainfuck" => Ok(Self::Brainfuck),
+            "ook" => Ok(Self::Tokens(TokenTable::ook())),
+            "blub" => Ok(Self::Tokens(TokenTable::blub())),
+            path => TokenTable::load(Path::new(path)).map(Self::Tokens),
+        }
+    }
+}
+
+impl Language {
+    /// Splits `src` at the first `!`, or the token for it, into the code and the input after it,
+    /// for [`Dialect::inline_input`]
+    pub fn split_inline_input<'a>(&self, src: &'a str) -> (&'a str, Option<&'a str>) {
+        match self {
+            Self::Brainfuck => parse::split_inline_input(src),
+            Self::Tokens(table) => {
+                match table.tokenize(src).find(|&(_, command)| command == b'!') {
+                    Some((span, _)) => (&src[..span.start()], Some(&src[span.end()..])),
+                    None => (src, None),
+                }
+            }
+        }
+    }
+}
+
+/// The commands that can be written with tokens
+const COMMANDS: &[u8] = b"+-<>.,[]#!():";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenTable {
+    /// the words of every token, with its command
+    tokens: Vec<(Vec<String>, u8)>,
+}
+
+impl TokenTable {
+    pub fn ook() -> Self {
+        Self::with_word("Ook")
+    }
+
+    pub fn blub() -> Self {
+        Self::with_word("Blub")
+    }
+
+    /// Ook! and Blub use the same pairs of punctuation after their word
+    fn with_word(word: &str) -> Self {
+        let pairs = [
+            (">", ".?"),
+            ("<", "?."),
+            ("+", ".."),
+            ("-", "!!"),
+            (".", "!."),
+            (",", ".!"),
+            ("[", "!?"),
+            ("]", "?!"),
+        ];
+        Self {
+            tokens: pairs
+                .iter()
+                .map(|(command, punctuation)| {
+                    let mut punctuation = punctuation.chars();
+                    let words = [punctuation.next(), punctuation.next()]
+                        .map(|punct| format!("{word}{}", punct.unwrap()));
+                    (words.to_vec(), command.as_bytes()[0])
+                })
+                .collect(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let src = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read token table {}: {err}", path.display()))?;
+        Self::from_toml(&src)
+    }
+
+    pub fn from_toml(src: &str) -> Result<Self, String> {
+        let value = src
+            .parse::<toml::Value>()
+            .map_err(|err| format!("Invalid token table: {err}"))?;
+        let table = value
+            .as_table()
+            .ok_or_else(|| "Invalid token table: expected a table".to_owned())?;
+
+        let mut tokens = Vec::new();
+        for (command, token) in table {
+            let command = match command.as_bytes() {
+                [command] if COMMANDS.contains(command) => *command,
+                _ => return Err(format!("Invalid command in token table: '{command}'")),
+            };
+            let words = token
+                .as_str()
+                .map(|token| {
+                    token
+                        .split_whitespace()
+                        .map(str::to_owned)
+                        .collect::<Vec<_>>()
+                })
+                .filter(|words| !words.is_empty())
+                .ok_or_else(|| format!("Invalid token for '{}'", command as char))?;
+            tokens.push((words, command));
+        }
+        Ok(Self { tokens })
+    }
+
+    /// The commands of the tokens in `src`, with the spans of the tokens
+    pub fn tokenize<'a>(&'a self, src: &'a str) -> impl Iterator<Item = (Span, u8)> + 'a {
+        let mut pos = 0;
+        std::iter::from_fn(move || {
+            while let Some(char) = src[pos..].chars().next() {
+                if let Some((end, command)) = self.longest_token_at(src, pos) {
+                    let span = Span::start_end(pos, end);
+                    pos = end;
+                    return Some((span, command));
+                }
+                pos += char.len_utf8();
+            }
+            None
+        })
+    }
+
+    /// The end of the longest token starting at `start`, and its command
+    fn longest_token_at(&self, src: &str, start: usize) -> Option<(usize, u8)> {
+        // a token that starts or ends with a letter can't continue a word of the comments
+        let in_word = |before: Option<char>, edge: Option<char>| {
+            before.is_some_and(char::is_alphanumeric) && edge.is_some_and(char::is_alphanumeric)
+        };
+        let before_start = src[..start].chars().next_back();
+
+        self.tokens
+            .iter()
+            .filter_map(|(words, command)| {
+                let mut pos = start;
+                for (i, word) in words.iter().enumerate() {
+                    if i > 0 {
+                        let rest = &src[pos..];
+                        let whitespace = rest.len() - rest.trim_start().len();
+                        if whitespace == 0 {
+                            return None;
+                        }
+                        pos += whitespace;
+                    }
+                    if !src[pos..].starts_with(word.as_str()) {
+                        return None;
+                    }
+                    pos += word.len();
+                }
+
+                let first = words.first()?.chars().next();
+                let last = words.last()?.chars().next_back();
+                if in_word(before_start, first) || in_word(src[pos..].chars().next(), last) {
+                    return None;
+                }
+                Some((pos, *command))
+            })
+            .max_by_key(|&(end, _)| end)
+    }
+}
+
+/// Parses `src` written in `language`
+pub fn parse<'ast>(
+    alloc: &'ast Bump,
+    src: &str,
+    language: &Language,
+    dialect: Dialect,
+) -> Result<Ast<'ast>, ParseError> {
+    match language {
+        Language::Brainfuck => parse::parse_dialect(alloc, src.bytes().enumerate(), dialect),
+        Language::Tokens(table) => parse::parse_tokens(alloc, table.tokenize(src), dialect),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use super::{Language, TokenTable};
+    use crate::{hir, lir, parse::Dialect};
+
+    /// Writes brainfuck with the tokens of the table, one per line
+    fn translate(bf: &str, table: &TokenTable) -> String {
+        bf.bytes()
+            .filter_map(|byte| {
+                let (words, _) = table.tokens.iter().find(|(_, command)| *command == byte)?;
+                Some(words.join(" "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn run(src: &str, language: &Language) -> Vec<u8> {
+        let alloc = Bump::new();
+        let ast = super::parse(&alloc, src, language, Dialect::default()).unwrap();
+        let hir = hir::optimized_hir(&alloc, &ast);
+        let lir = lir::generate(&hir);
+        let mut stdout = Vec::new();
+        lir::interpreter::run(&lir, &mut stdout, [].as_slice(), |_| {});
+        stdout
+    }
+
+    #[test]
+    fn ook_spans() {
+        let alloc = Bump::new();
+        let src = "Ook. Ook. Ook! Ook? a comment\nOok! Ook!\n  Ook?   Ook!";
+        let ast = super::parse(&alloc, src, &"ook".parse().unwrap(), Dialect::default());
+        insta::assert_debug_snapshot!(ast);
+    }
+
+    #[test]
+    fn fizzbuzz_in_isomorphs() {
+        let bf = include_str!("../benches/fizzbuzz.bf");
+        let expected = run(bf, &Language::Brainfuck);
+
+        let table = TokenTable::from_toml(
+            r#"
+            "+" = "plus"
+            "-" = "minus"
+            ">" = "right"
+            "<" = "left"
+            "." = "print"
+            "," = "read"
+            "[" = "while"
+            "]" = "end while"
+            "#,
+        )
+        .unwrap();
+
+        for table in [TokenTable::ook(), TokenTable::blub(), table] {
+            let translated = translate(bf, &table);
+            assert_eq!(run(&translated, &Language::Tokens(table)), expected);
+        }
+    }
+
+    #[test]
+    fn tokens_in_words() {
+        let table = TokenTable::from_toml(
+            r#"
+            "+" = "plus"
+            "." = "print"
+            "[" = "while"
+            "]" = "end"
+            "#,
+        )
+        .unwrap();
+        let src = "plus plus, a surplus of plus\nprint # my friend plusses the ending\n";
+        let commands = table
+            .tokenize(src)
+            .map(|(_, command)| command)
+            .collect::<Vec<_>>();
+        assert_eq!(commands, b"+++.");
+    }
+
+    #[test]
+    fn inline_input() {
+        let table = TokenTable::from_toml(
+            r#"
+            "," = "read"
+            "." = "print"
+            "[" = "while"
+            "]" = "end"
+            "!" = "input follows"
+            "#,
+        )
+        .unwrap();
+        let language = Language::Tokens(table);
+        let src = "read while print read end input follows\nhi! \0";
+        assert_eq!(
+            language.split_inline_input(src),
+            ("read while print read end ", Some("\nhi! \0"))
+        );
+        assert_eq!(
+            Language::Brainfuck.split_inline_input(",[.,]!hi"),
+            (",[.,]", Some("hi"))
+        );
+    }
+
+    #[test]
+    fn invalid_tables() {
+        assert!(TokenTable::from_toml(r#""x" = "plus""#).is_err());
+        assert!(TokenTable::from_toml(r#""+" = 1"#).is_err());
+        assert!(TokenTable::from_toml(r#""+" = " ""#).is_err());
+    }
+}
@@ -1,40 +1,332 @@
-#![feature(allocator_api, let_else)]
-#![feature(nonzero_ops)]
 #![deny(unsafe_op_in_unsafe_fn)]
 #![warn(rust_2018_idioms)]
 
 use std::{
-    fmt::Display,
-    io::{Read, Write},
-    path::PathBuf,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
     str::FromStr,
+    time::{Duration, Instant},
 };
 
 use bumpalo::Bump;
-use owo_colors::OwoColorize;
 
-use crate::parse::ParseError;
+use crate::{
+    isomorph::Language,
+    lir::{
+        interpreter::{FinalState, ProfileCollector},
+        Lir,
+    },
+    parse::Dialect,
+    profile::{LoopCollector, Profile, ProfileFormat},
+    watch::{WatchLogger, Watchpoint},
+};
 
+pub mod debugger;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod difftest;
+mod encoding;
+pub mod format;
 pub mod hir;
+pub mod isomorph;
 pub mod lir;
 mod mir;
 pub mod parse;
+pub mod profile;
+mod program;
+pub mod textgen;
+pub mod trace;
+pub mod vm;
+pub mod watch;
+
+pub use program::{CompileError, CompileOptions, Program, RunOptions};
+pub use vm::Vm;
 
-#[derive(clap::Parser, Default)]
+#[derive(clap::Parser)]
 #[clap(author, about)]
 pub struct Args {
-    /// Print colored source code depending on how often it was run.
-    /// Makes the interpreter ~30% slower.
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Run the program
+    ///
+    /// Reading after the end of the input leaves the cell unchanged.
+    Run {
+        #[clap(flatten)]
+        source: Source,
+        #[clap(flatten)]
+        options: RunArgs,
+        #[clap(flatten)]
+        input: InputArgs,
+    },
+    /// Only parse the program, and report whether it is valid
+    Check {
+        #[clap(flatten)]
+        source: Source,
+        #[clap(flatten)]
+        options: CompileArgs,
+    },
+    /// Print the program at an IR level (ast, hir, mir, lir), or the optimized HIR as
+    /// pseudo-code (pseudo)
+    Dump {
+        /// The IR level
+        level: DumpKind,
+        #[clap(flatten)]
+        source: Source,
+        #[clap(flatten)]
+        options: CompileArgs,
+    },
+    /// Run the program, and print the source code colored by how often it was run
+    Profile {
+        #[clap(flatten)]
+        source: Source,
+        #[clap(flatten)]
+        options: ProfileArgs,
+        #[clap(flatten)]
+        input: InputArgs,
+    },
+    /// Compile the program once and run it several times with the same input, discarding the
+    /// output, and print how long that took
+    Bench {
+        #[clap(flatten)]
+        source: Source,
+        #[clap(flatten)]
+        options: CompileArgs,
+        #[clap(flatten)]
+        input: InputArgs,
+        /// How often to run the program, at least once
+        #[clap(long, default_value = "10")]
+        runs: u32,
+    },
+    /// Print the program indented by loop depth
+    Fmt {
+        #[clap(flatten)]
+        source: Source,
+        /// The maximum line width
+        #[clap(long, default_value = "80")]
+        width: usize,
+    },
+    /// Print the program without comments and code that does nothing
+    Minify {
+        #[clap(flatten)]
+        source: Source,
+    },
+    /// Write the optimized program in another format instead of running it
+    Compile {
+        #[clap(flatten)]
+        source: Source,
+        #[clap(flatten)]
+        options: CompileArgs,
+        /// The format: brainfuck with repeat counts (rle), or compiled code that `run` can run
+        /// without compiling again (bytecode)
+        #[clap(long)]
+        emit: EmitKind,
+        /// The file to write to. Defaults to stdout, or for bytecode, to a `.bfc` file next to
+        /// the source file.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Run the program in an interactive debugger
+    Debug {
+        #[clap(flatten)]
+        source: Source,
+        #[clap(flatten)]
+        options: CompileArgs,
+    },
+    /// Replay a trace recorded with `run --trace`
+    Replay {
+        /// The trace file
+        trace: PathBuf,
+    },
+    /// Print a brainfuck program that prints the text
+    GenText {
+        /// The text to print
+        text: String,
+    },
+}
+
+/// Where the program comes from
+#[derive(clap::Args, Debug, Default)]
+pub struct Source {
+    /// The file with the program, or `-` to read it from stdin
+    #[clap(required_unless_present = "eval", conflicts_with = "eval")]
+    pub file: Option<PathBuf>,
+    /// The program itself, instead of a file
+    #[clap(short, long, value_name = "CODE")]
+    pub eval: Option<String>,
+}
+
+impl Source {
+    /// Reads the program from the file, stdin, or the argument
+    pub fn read(&self) -> io::Result<String> {
+        match (&self.eval, &self.file) {
+            (Some(code), _) => Ok(code.clone()),
+            (None, Some(file)) if file.as_os_str() == "-" => io::read_to_string(io::stdin()),
+            (None, Some(file)) => fs::read_to_string(file),
+            (None, None) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no program given",
+            )),
+        }
+    }
+
+    /// Whether the program is read from stdin, so stdin can't be the input of the program
+    pub fn is_stdin(&self) -> bool {
+        self.eval.is_none() && matches!(&self.file, Some(file) if file.as_os_str() == "-")
+    }
+
+    /// The file with the program, if it's compiled code from `compile --emit bytecode`
+    pub fn bytecode(&self) -> Option<&Path> {
+        match &self.file {
+            Some(file) if self.eval.is_none() && file.extension() == Some("bfc".as_ref()) => {
+                Some(file)
+            }
+            _ => None,
+        }
+    }
+
+    /// The name of the file for output formats that mention it, empty for inline programs
+    pub fn name(&self) -> String {
+        match (&self.eval, &self.file) {
+            (None, Some(file)) => file.display().to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// How the program is compiled
+#[derive(clap::Args, Debug, Default)]
+pub struct CompileArgs {
+    /// Enable extensions to the classic commands, comma separated: `debug` for `#`, `input` for
+    /// `!`, `pbrain` for procedures with `(`, `)` and `:` and `rle` for repeat counts like `+200`
+    #[clap(long, default_value = "classic")]
+    pub dialect: Dialect,
+    /// The language of the program: brainfuck, ook, blub, or the path to a TOML token table
+    #[clap(long, default_value = "brainfuck")]
+    pub language: Language,
+}
+
+impl CompileArgs {
+    pub fn options(&self) -> CompileOptions {
+        CompileOptions {
+            dialect: self.dialect,
+            language: self.language.clone(),
+        }
+    }
+}
+
+/// Where the input of the program comes from, instead of stdin or the inline input after the `!`
+#[derive(clap::Args, Debug, Default)]
+pub struct InputArgs {
+    /// Read the input from a file
+    #[clap(long, value_name = "FILE", conflicts_with = "input-string")]
+    pub input: Option<PathBuf>,
+    /// Use the string as the input. Supports the escapes `\n`, `\r`, `\t`, `\0`, `\\` and `\xHH`
+    /// for any byte.
+    #[clap(long, value_name = "STR")]
+    pub input_string: Option<ByteString>,
+}
+
+impl InputArgs {
+    /// Reads the input from the file or the argument, `None` if the input is stdin
+    pub fn read(&self) -> io::Result<Option<Vec<u8>>> {
+        match (&self.input, &self.input_string) {
+            (Some(file), _) => fs::read(file).map(Some),
+            (None, Some(string)) => Ok(Some(string.0.clone())),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Bytes written as a string with escapes, see [`InputArgs::input_string`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteString(pub Vec<u8>);
+
+impl FromStr for ByteString {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = Vec::new();
+        let mut chars = s.chars();
+        while let Some(char) = chars.next() {
+            if char != '\\' {
+                bytes.extend(char.encode_utf8(&mut [0; 4]).as_bytes());
+                continue;
+            }
+            let byte = match chars.next() {
+                Some('n') => b'\n',
+                Some('r') => b'\r',
+                Some('t') => b'\t',
+                Some('0') => 0,
+                Some('\\') => b'\\',
+                Some('x') => {
+                    let hex = chars.by_ref().take(2).collect::<String>();
+                    match u8::from_str_radix(&hex, 16) {
+                        Ok(byte)
+                            if hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+                        {
+                            byte
+                        }
+                        _ => return Err(format!("Invalid hex escape: '\\x{hex}'")),
+                    }
+                }
+                Some(other) => return Err(format!("Invalid escape: '\\{other}'")),
+                None => return Err("Unfinished escape at the end".to_owned()),
+            };
+            bytes.push(byte);
+        }
+        Ok(Self(bytes))
+    }
+}
+
+#[derive(clap::Args, Default)]
+pub struct RunArgs {
+    #[clap(flatten)]
+    pub compile: CompileArgs,
+    /// Log whenever the cell is written to, or with `cell=value`, when it changes to the value.
+    /// Writes are merged by the optimizer, but values in between, like the 2 of `+++`, still count.
     #[clap(short, long)]
-    pub profile: bool,
-    /// Dump the IR info (ast, hir, mir, lir)
-    #[clap(long)]
-    pub dump: Option<DumpKind>,
+    pub watch: Vec<Watchpoint>,
+    /// Record the execution to a trace file, which can be replayed with `replay`. Only for
+    /// classic brainfuck, and not together with `--watch` or `--mir`.
+    #[clap(long, conflicts_with_all = &["dialect", "language", "watch", "mir"])]
+    pub trace: Option<PathBuf>,
     /// Use experimental mid-level IR
     #[clap(long)]
     pub mir: bool,
-    /// The file to run
-    pub file: PathBuf,
+}
+
+impl RunArgs {
+    /// The first option that only affects compiling, so it can't be used when running bytecode
+    /// from `compile --emit bytecode`
+    pub fn bytecode_conflict(&self) -> Option<&'static str> {
+        if self.trace.is_some() {
+            Some("--trace")
+        } else if self.mir {
+            Some("--mir")
+        } else if self.compile.dialect != Dialect::default() {
+            Some("--dialect")
+        } else if self.compile.language != Language::default() {
+            Some("--language")
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(clap::Args, Default)]
+pub struct ProfileArgs {
+    #[clap(flatten)]
+    pub compile: CompileArgs,
+    /// The format of the profile (color, json, callgrind, folded, loops)
+    #[clap(long, default_value = "color")]
+    pub format: ProfileFormat,
+    /// Write the profile to a file instead of stdout
+    #[clap(long)]
+    pub out: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -43,6 +335,8 @@ pub enum DumpKind {
     Hir,
     Mir,
     Lir,
+    /// the optimized HIR as readable pseudo-code
+    Pseudo,
 }
 
 impl FromStr for DumpKind {
@@ -54,112 +348,278 @@ impl FromStr for DumpKind {
             "hir" => Ok(Self::Hir),
             "mir" => Ok(Self::Mir),
             "lir" => Ok(Self::Lir),
+            "pseudo" => Ok(Self::Pseudo),
             other => Err(format!("Invalid IR level: '{other}'")),
         }
     }
 }
 
-type BumpVec<'a, T> = Vec<T, &'a Bump>;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmitKind {
+    /// brainfuck with repeat counts, see the `rle` dialect
+    Rle,
+    /// the compiled code, see [`lir::bytecode`]
+    Bytecode,
+}
+
+impl FromStr for EmitKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rle" => Ok(Self::Rle),
+            "bytecode" => Ok(Self::Bytecode),
+            other => Err(format!("Invalid emit format: '{other}'")),
+        }
+    }
+}
+
+type BumpVec<'a, T> = bumpalo::collections::Vec<'a, T>;
 
 pub enum UseProfile {
     Yes,
     No,
 }
 
-pub fn run<R, W>(src: &str, stdout: W, stdin: R, config: &Args) -> Result<(), ParseError>
+/// Runs the program. `input` comes from `--input` or `--input-string`, and replaces both the
+/// inline input and `stdin`.
+pub fn run<R, W>(
+    src: &str,
+    stdout: W,
+    stdin: R,
+    input: Option<Vec<u8>>,
+    options: &RunArgs,
+) -> Result<(), CompileError>
 where
     W: Write,
     R: Read,
 {
-    let ast_alloc = Bump::new();
+    if options.mir {
+        let (code, _) = split_inline_input(src, &options.compile);
+        let ast_alloc = Bump::new();
+        let parsed = isomorph::parse(
+            &ast_alloc,
+            code,
+            &options.compile.language,
+            options.compile.dialect,
+        )?;
+        let hir_alloc = Bump::new();
+        let optimized_hir = hir::optimized_hir(&hir_alloc, &parsed);
+        let mir_alloc = Bump::new();
+        mir::optimized_mir(&mir_alloc, &optimized_hir);
+    }
 
-    let parsed = parse::parse(&ast_alloc, src.bytes().enumerate())?;
+    let program = Program::compile(src, &options.compile.options())?;
+    let run_options = RunOptions {
+        watch: options.watch.clone(),
+        input,
+    };
+    program.run(&run_options, stdin, stdout);
+    Ok(())
+}
+
+/// Only parses the program
+pub fn check(src: &str, options: &CompileArgs) -> Result<(), CompileError> {
+    let (src, _) = split_inline_input(src, options);
+    let alloc = Bump::new();
+    isomorph::parse(&alloc, src, &options.language, options.dialect)?;
+    Ok(())
+}
+
+/// Prints the program at the IR `level`
+pub fn dump(src: &str, level: DumpKind, options: &CompileArgs) -> Result<(), CompileError> {
+    let (src, _) = split_inline_input(src, options);
+
+    let ast_alloc = Bump::new();
+    let parsed = isomorph::parse(&ast_alloc, src, &options.language, options.dialect)?;
 
-    if let Some(DumpKind::Ast) = config.dump {
+    if level == DumpKind::Ast {
         println!("{parsed:#?}");
         return Ok(());
     }
 
     let hir_alloc = Bump::new();
-
     let optimized_hir = hir::optimized_hir(&hir_alloc, &parsed);
 
-    if let Some(DumpKind::Hir) = config.dump {
-        println!("{}", dbg_pls::color(&optimized_hir));
-        return Ok(());
+    match level {
+        DumpKind::Ast => unreachable!("returned above"),
+        DumpKind::Hir => println!("{}", dbg_pls::color(&optimized_hir)),
+        DumpKind::Pseudo => print!("{}", hir::pseudo::render(&optimized_hir)),
+        DumpKind::Mir => {
+            let mir_alloc = Bump::new();
+            let mir = mir::optimized_mir(&mir_alloc, &optimized_hir);
+            println!("{mir:#?}");
+        }
+        DumpKind::Lir => println!("{:#?}", lir::generate(&optimized_hir)),
     }
+    Ok(())
+}
 
-    drop(parsed);
-    drop(ast_alloc);
+/// Runs the program, and writes the profile to `options.out` or after the output to `stdout`.
+/// `input` replaces the inline input and `stdin`, like for [`run`]. `file_name` is mentioned by
+/// some formats.
+pub fn profile<R, W>(
+    src: &str,
+    mut stdout: W,
+    stdin: R,
+    input: Option<Vec<u8>>,
+    options: &ProfileArgs,
+    file_name: &str,
+) -> Result<(), CompileError>
+where
+    W: Write,
+    R: Read,
+{
+    let (code, _) = split_inline_input(src, &options.compile);
+    let program = Program::compile(src, &options.compile.options())?;
+    let lir = program.lir();
+    let stdin = match input.as_deref().or(program.inline_input()) {
+        Some(input) => Input::Inline(input),
+        None => Input::Stdin(stdin),
+    };
+
+    let (block_counts, loop_stats) = match options.format {
+        ProfileFormat::Json | ProfileFormat::Loops => {
+            let mut collector = LoopCollector::new(lir);
+            execute(lir, &mut stdout, stdin, &mut collector, &[]);
+            let (block_counts, loop_stats) = collector.finish();
+            (block_counts, Some(loop_stats))
+        }
+        _ => {
+            let mut block_counts = vec![0; lir.debug().len()];
+            execute(
+                lir,
+                &mut stdout,
+                stdin,
+                |ip| unsafe {
+                    *block_counts.get_unchecked_mut(ip) += 1;
+                },
+                &[],
+            );
+            (block_counts, None)
+        }
+    };
 
-    if config.dump == Some(DumpKind::Mir) || config.mir {
-        let mir_alloc = Bump::new();
-        let mir = mir::optimized_mir(&mir_alloc, &optimized_hir);
-        if config.dump == Some(DumpKind::Mir) {
-            println!("{mir:#?}");
+    let mut profile = Profile::new(code, lir, &block_counts);
+    if let Some(loop_stats) = loop_stats {
+        profile.set_loop_stats(loop_stats);
+    }
+    let result = match &options.out {
+        Some(path) => {
+            File::create(path).and_then(|file| profile.write(options.format, file_name, file))
         }
+        None => profile.write(options.format, file_name, &mut stdout),
+    };
+    if let Err(err) = result {
+        eprintln!("error: Failed to write profile: {err}");
     }
 
-    let cg_alloc = Bump::new();
-
-    let lir = lir::generate(&cg_alloc, &optimized_hir);
+    Ok(())
+}
 
-    if let Some(DumpKind::Lir) = config.dump {
-        println!("{lir:#?}");
-        return Ok(());
+/// Compiles the program once and runs it `runs` times with `input`, and prints how long that
+/// took
+pub fn bench(
+    src: &str,
+    input: &[u8],
+    options: &CompileArgs,
+    runs: u32,
+) -> Result<(), CompileError> {
+    let start = Instant::now();
+    let program = Program::compile(src, &options.options())?;
+    println!("compile: {:.2?}", start.elapsed());
+
+    let runs = runs.max(1);
+    let mut times = Vec::new();
+    for _ in 0..runs {
+        let start = Instant::now();
+        program.run(&RunOptions::default(), input, io::sink());
+        times.push(start.elapsed());
     }
 
-    drop(optimized_hir);
-    drop(hir_alloc);
-
-    match config.profile {
-        true => {
-            let mut code_profile_count = vec![0; lir.debug().len()];
+    let mean = times.iter().sum::<Duration>() / runs;
+    let min = times.iter().min().unwrap();
+    let max = times.iter().max().unwrap();
+    println!("run: {mean:.2?} on average, {min:.2?} to {max:.2?} over {runs} runs");
+    Ok(())
+}
 
-            lir::interpreter::run(&lir, stdout, stdin, |ip| unsafe {
-                *code_profile_count.get_unchecked_mut(ip) += 1;
-            });
+/// Writes the optimized program in the format `kind` to `out`
+pub fn emit<W>(
+    src: &str,
+    kind: EmitKind,
+    options: &CompileArgs,
+    mut out: W,
+) -> Result<(), CompileError>
+where
+    W: Write,
+{
+    let result = match kind {
+        EmitKind::Rle => {
+            let (src, _) = split_inline_input(src, options);
+            let ast_alloc = Bump::new();
+            let parsed = isomorph::parse(&ast_alloc, src, &options.language, options.dialect)?;
+            let hir_alloc = Bump::new();
+            let optimized_hir = hir::optimized_hir(&hir_alloc, &parsed);
+            writeln!(out, "{}", hir::rle::emit(&optimized_hir))
+        }
+        EmitKind::Bytecode => Program::compile(src, &options.options())?.write_bytecode(out),
+    };
+    if let Err(err) = result {
+        eprintln!("error: Failed to write program: {err}");
+    }
+    Ok(())
+}
 
-            let mut src_profile_count = vec![0u64; src.len()];
+/// Splits `src` into the code and the input after the `!`, if the dialect has inline input
+fn split_inline_input<'a>(src: &'a str, options: &CompileArgs) -> (&'a str, Option<&'a str>) {
+    match options.dialect.inline_input {
+        true => options.language.split_inline_input(src),
+        false => (src, None),
+    }
+}
 
-            for (stmt_span, stmt_count) in lir.debug().iter().zip(&code_profile_count) {
-                for i in &mut src_profile_count[stmt_span.start()..stmt_span.end()] {
-                    *i += stmt_count;
-                }
-            }
+/// The input of the program, either `stdin` or the inline input after the `!`
+enum Input<'a, R> {
+    Stdin(R),
+    Inline(&'a [u8]),
+}
 
-            let max = src_profile_count.iter().max().copied().unwrap_or(0);
-            println!("\n\n---------------- Profile ----------------");
-            for (char, value) in src.bytes().zip(src_profile_count) {
-                print!("{}", color_by_profile(char as char, value, max));
-            }
-        }
-        false => {
-            lir::interpreter::run(&lir, stdout, stdin, |_| {});
+impl<R: Read> Read for Input<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Input::Stdin(stdin) => stdin.read(buf),
+            Input::Inline(input) => input.read(buf),
         }
     }
-
-    Ok(())
 }
 
-fn color_by_profile(char: char, value: u64, max: u64) -> impl Display {
-    let max = max as f64;
-    let value = value as f64;
-    let ratio = value / max;
-    let logged = -ratio.log10();
-    let logged = (logged * 100.) as u64;
-
-    match logged {
-        0..=15 => char.bright_red().to_string(),
-        16..=70 => char.yellow().to_string(),
-        71..=300 => char.green().to_string(),
-        _ => char.default_color().to_string(),
+fn execute<W, R, P>(
+    lir: &Lir,
+    stdout: W,
+    stdin: R,
+    profile_collector: P,
+    watch: &[Watchpoint],
+) -> FinalState
+where
+    W: Write,
+    R: Read,
+    P: ProfileCollector,
+{
+    // only pay for the watchpoints if there are any
+    if watch.is_empty() {
+        lir::interpreter::run(lir, stdout, stdin, profile_collector)
+    } else {
+        let logger = WatchLogger::new(watch, lir, std::io::stderr());
+        lir::interpreter::run_watched(lir, stdout, stdin, profile_collector, logger)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Args;
+    use clap::Parser;
+
+    use crate::{Args, ByteString, Command, CompileArgs, RunArgs};
 
     #[test]
     fn fizzbuzz() {
@@ -167,7 +627,14 @@ mod tests {
         let mut stdout = Vec::new();
         let stdin = [];
 
-        super::run(str, &mut stdout, stdin.as_slice(), &Args::default()).unwrap();
+        super::run(
+            str,
+            &mut stdout,
+            stdin.as_slice(),
+            None,
+            &RunArgs::default(),
+        )
+        .unwrap();
 
         insta::assert_debug_snapshot!(String::from_utf8(stdout));
     }
@@ -178,8 +645,153 @@ mod tests {
         let mut stdout = Vec::new();
         let stdin = [];
 
-        super::run(str, &mut stdout, stdin.as_slice(), &Args::default()).unwrap();
+        super::run(
+            str,
+            &mut stdout,
+            stdin.as_slice(),
+            None,
+            &RunArgs::default(),
+        )
+        .unwrap();
 
         insta::assert_debug_snapshot!(String::from_utf8(stdout));
     }
+
+    #[test]
+    fn pbrain_procedures() {
+        // procedure 1 prints an `A` using the two cells to the right
+        let str = "+(>[-]++++++++[>++++++++<-]>+.[-]<<)::";
+        let mut stdout = Vec::new();
+        let stdin = [];
+        let config = RunArgs {
+            compile: CompileArgs {
+                dialect: "pbrain".parse().unwrap(),
+                ..CompileArgs::default()
+            },
+            ..RunArgs::default()
+        };
+
+        super::run(str, &mut stdout, stdin.as_slice(), None, &config).unwrap();
+
+        assert_eq!(stdout, b"AA");
+    }
+
+    #[test]
+    fn inline_input() {
+        let str = ",.,.!hi";
+        let stdin = [];
+
+        for mir in [false, true] {
+            let mut stdout = Vec::new();
+            let config = RunArgs {
+                compile: CompileArgs {
+                    dialect: "input".parse().unwrap(),
+                    ..CompileArgs::default()
+                },
+                mir,
+                ..RunArgs::default()
+            };
+
+            super::run(str, &mut stdout, stdin.as_slice(), None, &config).unwrap();
+
+            assert_eq!(stdout, b"hi");
+        }
+    }
+
+    #[test]
+    fn end_of_input() {
+        let args = Args::try_parse_from(["brainfuck", "run", "-e", ",.,.", "--input-string", "a"])
+            .unwrap();
+        let Command::Run {
+            source,
+            input,
+            options,
+        } = args.command
+        else {
+            unreachable!();
+        };
+
+        let mut stdout = Vec::new();
+        let src = source.read().unwrap();
+        let input = input.read().unwrap();
+        super::run(&src, &mut stdout, [].as_slice(), input, &options).unwrap();
+
+        assert_eq!(stdout, b"aa");
+    }
+
+    #[test]
+    fn subcommands() {
+        let parse = |args: &[&str]| Args::try_parse_from([&["brainfuck"], args].concat());
+
+        let args = parse(&["dump", "lir", "-e", "+[-]"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::Dump { level: super::DumpKind::Lir, source, .. }
+                if source.eval.as_deref() == Some("+[-]")
+        ));
+
+        let args = parse(&["run", "-", "--dialect", "pbrain"]).unwrap();
+        assert!(matches!(args.command, Command::Run { source, .. } if source.is_stdin()));
+
+        let args = parse(&["compile", "a.bf", "--emit", "bytecode"]).unwrap();
+        assert!(matches!(args.command, Command::Compile { .. }));
+
+        let args = parse(&["run", "-e", ",[.,]", "--input-string", "a\\x00"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::Run { input, .. } if input.read().unwrap() == Some(b"a\0".to_vec())
+        ));
+        assert!(parse(&["run", "a.bf", "--input", "in", "--input-string", "x"]).is_err());
+
+        // traces are only for classic brainfuck
+        assert!(parse(&["run", "a.bf", "--trace", "a.trace"]).is_ok());
+        for option in [
+            ["--dialect", "pbrain"],
+            ["--language", "ook"],
+            ["--watch", "0"],
+        ] {
+            assert!(
+                parse(&[&["run", "a.bf", "--trace", "a.trace"], &option[..]].concat()).is_err()
+            );
+        }
+        assert!(parse(&["run", "a.bf", "--trace", "a.trace", "--mir"]).is_err());
+
+        // bytecode is already compiled
+        let bytecode_conflict = |option: &[&str]| {
+            let args = parse(&[&["run", "a.bfc"], option].concat()).unwrap();
+            match args.command {
+                Command::Run { options, .. } => options.bytecode_conflict(),
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(bytecode_conflict(&["--watch", "0"]), None);
+        assert_eq!(bytecode_conflict(&["--dialect", "classic"]), None);
+        assert_eq!(bytecode_conflict(&["--trace", "a.trace"]), Some("--trace"));
+        assert_eq!(bytecode_conflict(&["--mir"]), Some("--mir"));
+        assert_eq!(bytecode_conflict(&["--dialect", "rle"]), Some("--dialect"));
+        assert_eq!(
+            bytecode_conflict(&["--language", "ook"]),
+            Some("--language")
+        );
+
+        // exactly one of a file and inline code
+        assert!(parse(&["run"]).is_err());
+        assert!(parse(&["run", "a.bf", "-e", "+"]).is_err());
+        assert!(parse(&["compile", "a.bf"]).is_err());
+    }
+
+    #[test]
+    fn byte_string() {
+        let parse = |s: &str| s.parse::<ByteString>().map(|bytes| bytes.0);
+
+        assert_eq!(
+            parse("hi\\n\\x00\\xfF\\\\ä"),
+            Ok(b"hi\n\0\xff\\\xc3\xa4".to_vec())
+        );
+        assert!(parse("\\x4").is_err());
+        assert!(parse("\\xgg").is_err());
+        assert!(parse("\\x+f").is_err());
+        assert!(parse("\\q").is_err());
+        assert!(parse("end\\").is_err());
+    }
 }
@@ -0,0 +1,272 @@
+//! compiled programs on disk, so they can run without being parsed and optimized again
+//!
+//! A `.bfc` file contains the statements, their spans in the source, and the inline input of
+//! the program. Numbers are LEB128 varints:
+//!
+//! ```text
+//! b"BFCODE" version:u8
+//! stmt_count:varint stmt:[u8; 6]*          see `Stmt::encode`
+//! (span_start:varint span_len:varint)*     one for every statement
+//! has_inline_input:u8 (input_len:varint input:[u8])?
+//! ```
+//!
+//! The interpreter follows jumps without bounds checks, so the loader
+//! [verifies](Lir::verify) the code before anything can run it.
+
+use std::{
+    fmt::{Display, Formatter},
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+use crate::{
+    encoding::{self, write_bytes, write_varint},
+    lir::{Lir, Stmt, VerifyError},
+    parse::Span,
+};
+
+const MAGIC: &[u8] = b"BFCODE";
+const VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum BytecodeError {
+    Io(io::Error),
+    /// the file is not bytecode, or bytecode of an unsupported version
+    Invalid(&'static str),
+    /// the code could break the interpreter
+    Verify(VerifyError),
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BytecodeError::Io(err) => write!(f, "Failed to read or write bytecode: {err}"),
+            BytecodeError::Invalid(reason) => write!(f, "Invalid bytecode: {reason}"),
+            BytecodeError::Verify(err) => write!(f, "Invalid bytecode: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+impl From<io::Error> for BytecodeError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Writes `lir` and the inline input of its program to `out`
+pub fn write<W: Write>(lir: &Lir, inline_input: Option<&[u8]>, out: W) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION])?;
+
+    write_varint(&mut out, lir.stmts.len() as u64)?;
+    for stmt in &lir.stmts {
+        out.write_all(&stmt.encode())?;
+    }
+    for span in &lir.debug {
+        write_varint(&mut out, span.start() as u64)?;
+        write_varint(&mut out, span.len() as u64)?;
+    }
+
+    match inline_input {
+        Some(input) => {
+            out.write_all(&[1])?;
+            write_bytes(&mut out, input)?;
+        }
+        None => out.write_all(&[0])?,
+    }
+
+    out.flush()
+}
+
+/// Reads the code and inline input written by [`write`]
+pub fn read<R: Read>(input: R) -> Result<(Lir, Option<Vec<u8>>), BytecodeError> {
+    let mut input = BufReader::new(input);
+
+    let mut magic = [0; MAGIC.len()];
+    input
+        .read_exact(&mut magic)
+        .map_err(|_| BytecodeError::Invalid("not bytecode"))?;
+    if magic != MAGIC {
+        return Err(BytecodeError::Invalid("not bytecode"));
+    }
+    if read_u8(&mut input)? != VERSION {
+        return Err(BytecodeError::Invalid("unsupported version"));
+    }
+
+    let stmt_count = read_varint(&mut input)?;
+    // jumps are `u32`
+    if stmt_count > u64::from(u32::MAX) {
+        return Err(BytecodeError::Invalid("too many statements"));
+    }
+
+    // don't trust the count with the allocation
+    let mut stmts = Vec::new();
+    for _ in 0..stmt_count {
+        let mut bytes = [0; Stmt::ENCODED_LEN];
+        input.read_exact(&mut bytes).map_err(invalid)?;
+        let stmt = Stmt::decode(bytes).ok_or(BytecodeError::Invalid("unknown statement"))?;
+        stmts.push(stmt);
+    }
+
+    let mut debug = Vec::with_capacity(stmts.len());
+    for _ in 0..stmt_count {
+        let start = read_varint(&mut input)?;
+        let end = start.saturating_add(read_varint(&mut input)?);
+        if end > u64::from(u32::MAX) {
+            return Err(BytecodeError::Invalid("span out of range"));
+        }
+        debug.push(Span::start_end(start as usize, end as usize));
+    }
+
+    let inline_input = match read_u8(&mut input)? {
+        0 => None,
+        1 => Some(encoding::read_bytes(&mut input, usize::MAX).map_err(invalid)?),
+        _ => return Err(BytecodeError::Invalid("unknown inline input marker")),
+    };
+
+    let lir = Lir { stmts, debug };
+    lir.verify().map_err(BytecodeError::Verify)?;
+
+    Ok((lir, inline_input))
+}
+
+fn read_varint(input: &mut impl Read) -> Result<u64, BytecodeError> {
+    encoding::read_varint(input).map_err(invalid)
+}
+
+fn read_u8(input: &mut impl Read) -> Result<u8, BytecodeError> {
+    encoding::read_u8(input).map_err(invalid)
+}
+
+fn invalid(err: io::Error) -> BytecodeError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => BytecodeError::Invalid("unexpected end of bytecode"),
+        io::ErrorKind::InvalidData => BytecodeError::Invalid("number out of range"),
+        _ => BytecodeError::Io(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BytecodeError, MAGIC};
+    use crate::{
+        lir::{Lir, Stmt},
+        CompileOptions, Program,
+    };
+
+    fn compile(src: &str) -> Program {
+        Program::compile(src, &CompileOptions::default()).unwrap()
+    }
+
+    fn write(lir: &Lir, inline_input: Option<&[u8]>) -> Vec<u8> {
+        let mut bytecode = Vec::new();
+        super::write(lir, inline_input, &mut bytecode).unwrap();
+        bytecode
+    }
+
+    #[test]
+    fn roundtrip() {
+        let program = compile(include_str!("../../benches/fizzbuzz.bf"));
+        let lir = program.lir();
+
+        let (read, inline_input) = super::read(write(lir, Some(b"in")).as_slice()).unwrap();
+        assert_eq!(read.fingerprint(), lir.fingerprint());
+        assert_eq!(read.debug(), lir.debug());
+        assert_eq!(inline_input.as_deref(), Some(b"in".as_slice()));
+    }
+
+    #[test]
+    fn every_stmt() {
+        let stmts = [
+            Stmt::Add(1),
+            Stmt::Sub(2),
+            Stmt::AddOffset { offset: -3, n: 4 },
+            Stmt::SubOffset { offset: 5, n: 6 },
+            Stmt::MoveAddTo { offset: -7 },
+            Stmt::Right(8),
+            Stmt::Left(9),
+            Stmt::Out,
+            Stmt::In,
+            Stmt::SetN(10),
+            Stmt::JmpIfZero(11),
+            Stmt::JmpIfNonZero(12),
+            Stmt::DebugDump,
+            Stmt::DefineProc(13),
+            Stmt::Call,
+            Stmt::Return,
+            Stmt::End,
+        ];
+        for stmt in stmts {
+            let decoded = Stmt::decode(stmt.encode()).unwrap();
+            assert_eq!(format!("{decoded:?}"), format!("{stmt:?}"));
+        }
+    }
+
+    #[test]
+    fn invalid_bytecode() {
+        let program = compile(",[.,]");
+        let stmts = program.lir().stmts();
+        let valid = write(program.lir(), None);
+        // the magic, the version and the statement count
+        let stmt_pos = |ip: usize| MAGIC.len() + 2 + ip * Stmt::ENCODED_LEN;
+
+        let mut jump_out = valid.clone();
+        let jump = stmts
+            .iter()
+            .position(|stmt| matches!(stmt, Stmt::JmpIfZero(_)))
+            .unwrap();
+        jump_out[stmt_pos(jump) + 2] = 100;
+        let mut no_end = valid.clone();
+        no_end[stmt_pos(stmts.len() - 1)] = Stmt::Out.encode()[0];
+        let mut truncated = valid;
+        truncated.pop();
+
+        for bytecode in [b",[.,]".to_vec(), truncated] {
+            let result = super::read(bytecode.as_slice());
+            assert!(
+                matches!(result, Err(BytecodeError::Invalid(_))),
+                "{result:?}"
+            );
+        }
+        let stray_return = write(
+            &Lir {
+                stmts: vec![Stmt::Return, Stmt::End],
+                debug: vec![Default::default(); 2],
+            },
+            None,
+        );
+
+        for bytecode in [jump_out, no_end, stray_return] {
+            let result = super::read(bytecode.as_slice());
+            assert!(
+                matches!(result, Err(BytecodeError::Verify(_))),
+                "{result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn undefined_procedures() {
+        // calls procedure 0 and 1, but only defines 1, which prints the cell
+        let lir = Lir {
+            stmts: vec![
+                Stmt::Call,
+                Stmt::Add(1),
+                Stmt::DefineProc(5),
+                Stmt::Out,
+                Stmt::Return,
+                Stmt::Call,
+                Stmt::End,
+            ],
+            debug: vec![Default::default(); 7],
+        };
+        let program = Program::from_bytecode(write(&lir, None).as_slice()).unwrap();
+
+        let mut output = Vec::new();
+        program.run(&Default::default(), [].as_slice(), &mut output);
+        assert_eq!(output, [1]);
+    }
+}
@@ -1,54 +1,166 @@
 use std::{
-    io::{Read, Write},
+    io::{self, Read, Write},
     num::Wrapping,
 };
 
 use crate::lir::{Lir, Stmt};
 
-const MEM_SIZE: usize = 32_000;
+pub(crate) const MEM_SIZE: usize = 32_000;
 
 type Memory = [Wrapping<u8>; MEM_SIZE];
 
 // `repr(C)` to make sure rustc never reorders the fields weirdly
 // maybe useless, but seems to give tiny wins
 #[repr(C)]
-struct Interpreter<'lir, W, R, P> {
-    code: &'lir Lir<'lir>,
+pub(crate) struct Interpreter<'lir, W, R, P, H> {
+    code: &'lir Lir,
     profile_collector: P,
+    watcher: H,
     ip: usize,
     ptr: usize,
     mem: Memory,
     stdout: W,
     stdin: R,
+    /// the start of the body of every procedure, or `NO_PROC`
+    procedures: [u32; 256],
+    /// the ips to return to
+    call_stack: Vec<usize>,
 }
 
-pub fn run<W, R, P>(code: &Lir<'_>, stdout: W, stdin: R, profile_collector: P)
+const NO_PROC: u32 = u32::MAX;
+
+/// The tape and pointer after a program has run to its end
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FinalState {
+    pub ptr: usize,
+    pub mem: Vec<u8>,
+}
+
+/// Gets notified whenever a basic block is entered, for profiling.
+///
+/// Basic blocks start at the first statement and after every jump, no matter whether the jump
+/// was taken. All statements in a block run as often as the block is entered, so the count for
+/// every statement can be reconstructed afterwards, without paying for a call after every one.
+///
+/// Implemented for every `FnMut(usize)`, which only cares about the ip.
+pub trait ProfileCollector {
+    /// The basic block starting at `ip` is entered, with the pointer at `ptr` and `depth`
+    /// procedure calls running
+    fn block(&mut self, ip: usize, ptr: usize, depth: usize);
+}
+
+impl<F: FnMut(usize)> ProfileCollector for F {
+    #[inline(always)]
+    fn block(&mut self, ip: usize, _: usize, _: usize) {
+        self(ip);
+    }
+}
+
+/// Gets notified about every write to the tape, for example for watchpoints.
+///
+/// The implementation for `()` does nothing, and costs nothing at runtime.
+pub trait Watcher {
+    /// The statement at `ip`, with the pointer at `ptr`, changed the cell at `idx` from `old` to
+    /// `new`
+    fn write(&mut self, ip: usize, ptr: usize, idx: usize, old: u8, new: u8);
+}
+
+impl Watcher for () {
+    #[inline(always)]
+    fn write(&mut self, _: usize, _: usize, _: usize, _: u8, _: u8) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CellWrite {
+    pub idx: usize,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// Collects the writes of the statement that is being executed
+#[derive(Default)]
+pub(crate) struct WriteRecorder {
+    pub writes: [Option<CellWrite>; 2],
+}
+
+impl Watcher for WriteRecorder {
+    fn write(&mut self, _: usize, _: usize, idx: usize, old: u8, new: u8) {
+        let slot = self
+            .writes
+            .iter_mut()
+            .find(|write| write.is_none())
+            .expect("statements never write more than two cells");
+        *slot = Some(CellWrite { idx, old, new });
+    }
+}
+
+pub fn run<W, R, P>(code: &Lir, stdout: W, stdin: R, profile_collector: P) -> FinalState
+where
+    W: Write,
+    R: Read,
+    P: ProfileCollector,
+{
+    run_watched(code, stdout, stdin, profile_collector, ())
+}
+
+/// Like [`run`], but `watcher` is notified about every write to the tape
+pub fn run_watched<W, R, P, H>(
+    code: &Lir,
+    stdout: W,
+    stdin: R,
+    profile_collector: P,
+    watcher: H,
+) -> FinalState
 where
     W: Write,
     R: Read,
-    P: FnMut(usize),
+    P: ProfileCollector,
+    H: Watcher,
 {
-    let mut interpreter = Interpreter {
-        code,
-        ip: 0,
-        ptr: 0,
-        stdout,
-        stdin,
-        mem: [Wrapping(0u8); MEM_SIZE],
-        profile_collector,
-    };
+    let mut interpreter = Interpreter::new(code, stdout, stdin, profile_collector, watcher);
 
     // SAFETY: `Lir` can only be produced by the `crate::lir` module, which is trusted to not
-    // produce out of bounds jumps and put the `End` at the end
+    // produce out of bounds jumps and put the `End` at the end. Code loaded from elsewhere is
+    // checked with `Lir::verify`. Cells are always accessed in bounds, see `offset_idx`
     unsafe {
         interpreter.execute();
     }
+
+    FinalState {
+        ptr: interpreter.ptr,
+        mem: interpreter.mem.iter().map(|cell| cell.0).collect(),
+    }
 }
 
-impl<'c, W: Write, R: Read, P> Interpreter<'c, W, R, P>
+impl<'c, W: Write, R: Read, P, H> Interpreter<'c, W, R, P, H>
 where
-    P: FnMut(usize),
+    P: ProfileCollector,
+    H: Watcher,
 {
+    pub(crate) fn new(
+        code: &'c Lir,
+        stdout: W,
+        stdin: R,
+        mut profile_collector: P,
+        watcher: H,
+    ) -> Self {
+        // the first block starts right away
+        profile_collector.block(0, 0, 0);
+
+        Self {
+            code,
+            ip: 0,
+            ptr: 0,
+            stdout,
+            stdin,
+            mem: [Wrapping(0u8); MEM_SIZE],
+            profile_collector,
+            watcher,
+            procedures: [NO_PROC; 256],
+            call_stack: Vec::new(),
+        }
+    }
+
     unsafe fn execute(&mut self) {
         let stmts = self.code.stmts();
         loop {
@@ -58,71 +170,263 @@ where
             debug_assert!(self.ip < stmts.len());
             let instr = unsafe { *stmts.get_unchecked(self.ip) };
             self.ip += 1;
-            match instr {
-                Stmt::Add(n) => {
-                    *self.elem_mut() += n;
-                }
-                Stmt::Sub(n) => {
-                    *self.elem_mut() -= n;
-                }
-                Stmt::AddOffset { offset, n } => *self.elem_mut_offset(offset) += n,
-                Stmt::SubOffset { offset, n } => *self.elem_mut_offset(offset) -= n,
-                Stmt::MoveAddTo { offset } => {
-                    let value = self.elem();
+            if !self.execute_stmt(instr) {
+                break;
+            }
+        }
+    }
+
+    /// Executes a single statement, stopping in front of the `End`.
+    /// Returns `false` if the program has already ended.
+    pub(crate) fn step(&mut self) -> bool {
+        let instr = self.code.stmts()[self.ip];
+        if let Stmt::End = instr {
+            return false;
+        }
+        self.ip += 1;
+        self.execute_stmt(instr);
+        true
+    }
+
+    pub(crate) fn code(&self) -> &'c Lir {
+        self.code
+    }
+
+    pub(crate) fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub(crate) fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    pub(crate) fn watcher_mut(&mut self) -> &mut H {
+        &mut self.watcher
+    }
+
+    pub(crate) fn stdin(&self) -> &R {
+        &self.stdin
+    }
+
+    pub(crate) fn stdin_mut(&mut self) -> &mut R {
+        &mut self.stdin
+    }
+
+    pub(crate) fn stdout(&self) -> &W {
+        &self.stdout
+    }
+
+    pub(crate) fn stdout_mut(&mut self) -> &mut W {
+        &mut self.stdout
+    }
+
+    pub(crate) fn cell(&self, idx: usize) -> u8 {
+        self.mem[idx].0
+    }
+
+    pub(crate) fn set_cell(&mut self, idx: usize, value: u8) {
+        self.mem[idx] = Wrapping(value);
+    }
+
+    /// The number and start of every procedure that has been defined
+    pub(crate) fn procedures(&self) -> impl Iterator<Item = (u8, usize)> + '_ {
+        (0..=u8::MAX)
+            .zip(self.procedures)
+            .filter(|&(_, start)| start != NO_PROC)
+            .map(|(number, start)| (number, start as usize))
+    }
+
+    /// The start of procedure `number`, if it has been defined
+    pub(crate) fn procedure(&self, number: u8) -> Option<usize> {
+        match self.procedures[usize::from(number)] {
+            NO_PROC => None,
+            start => Some(start as usize),
+        }
+    }
+
+    /// `start` must be the index of a statement in the code
+    pub(crate) fn define_procedure(&mut self, number: u8, start: usize) {
+        assert!(start < self.code.stmts().len());
+        self.procedures[usize::from(number)] = start as u32;
+    }
+
+    pub(crate) fn undefine_procedure(&mut self, number: u8) {
+        self.procedures[usize::from(number)] = NO_PROC;
+    }
+
+    /// The ips that the running procedures return to, the innermost last
+    pub(crate) fn call_stack(&self) -> &[usize] {
+        &self.call_stack
+    }
+
+    /// `ip` must be the index of a statement in the code
+    pub(crate) fn push_call(&mut self, ip: usize) {
+        assert!(ip < self.code.stmts().len());
+        self.call_stack.push(ip);
+    }
+
+    pub(crate) fn pop_call(&mut self) -> Option<usize> {
+        self.call_stack.pop()
+    }
+
+    /// Moves the interpreter to a different position, used by the debugger to go back in time.
+    /// `ip` must be the index of a statement in the code.
+    pub(crate) fn set_position(&mut self, ip: usize, ptr: usize) {
+        assert!(ip < self.code.stmts().len() && ptr < MEM_SIZE);
+        self.ip = ip;
+        self.ptr = ptr;
+    }
+
+    /// Executes `instr`, `self.ip` must already point to the statement after it.
+    /// Returns `false` for `End`.
+    #[inline(always)]
+    fn execute_stmt(&mut self, instr: Stmt) -> bool {
+        match instr {
+            Stmt::Add(n) => {
+                let old = self.elem();
+                *self.elem_mut() += n;
+                self.watch(self.ptr, old);
+            }
+            Stmt::Sub(n) => {
+                let old = self.elem();
+                *self.elem_mut() -= n;
+                self.watch(self.ptr, old);
+            }
+            Stmt::AddOffset { offset, n } => {
+                let old = self.elem_mut_offset(offset).0;
+                *self.elem_mut_offset(offset) += n;
+                self.watch(self.offset_idx(offset), old);
+            }
+            Stmt::SubOffset { offset, n } => {
+                let old = self.elem_mut_offset(offset).0;
+                *self.elem_mut_offset(offset) -= n;
+                self.watch(self.offset_idx(offset), old);
+            }
+            Stmt::MoveAddTo { offset } => {
+                let value = self.elem();
+                // the loop this came from never touches the other cell if the current one is
+                // zero, so the other cell might not even be on the tape
+                if value != 0 {
                     *self.elem_mut() = Wrapping(0);
+                    self.watch(self.ptr, value);
+                    let old = self.elem_mut_offset(offset).0;
                     *self.elem_mut_offset(offset) += value;
+                    self.watch(self.offset_idx(offset), old);
                 }
-                Stmt::Right(n) => {
-                    self.ptr += n as usize;
-                    if self.ptr >= MEM_SIZE {
-                        self.ptr = 0;
-                    }
-                }
-                Stmt::Left(n) => {
-                    if self.ptr < n as usize {
-                        let diff = n as usize - self.ptr;
-                        self.ptr = MEM_SIZE - 1 - diff;
-                    } else {
-                        self.ptr -= n as usize;
+            }
+            Stmt::Right(n) => self.ptr = self.moved_idx(i64::from(n)),
+            Stmt::Left(n) => self.ptr = self.moved_idx(-i64::from(n)),
+            Stmt::Out => {
+                let char = self.elem() as char;
+                write!(self.stdout, "{char}").unwrap();
+                self.stdout.flush().unwrap();
+            }
+            Stmt::In => {
+                let mut buf = [0; 1];
+                match self.stdin.read_exact(&mut buf) {
+                    Ok(()) => {
+                        let old = self.elem();
+                        *self.elem_mut() = Wrapping(buf[0]);
+                        self.watch(self.ptr, old);
                     }
+                    // at the end of the input, the cell keeps its value
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {}
+                    Err(err) => panic!("failed to read input: {err}"),
                 }
-                Stmt::Out => {
-                    let char = self.elem() as char;
-                    write!(self.stdout, "{char}").unwrap();
-                    self.stdout.flush().unwrap();
-                }
-                Stmt::In => {
-                    let mut buf = [0; 1];
-                    self.stdin.read_exact(&mut buf).unwrap();
-                    *self.elem_mut() = Wrapping(buf[0]);
+            }
+            Stmt::SetN(n) => {
+                let old = self.elem();
+                *self.elem_mut() = Wrapping(n);
+                self.watch(self.ptr, old);
+            }
+            Stmt::JmpIfZero(pos) => {
+                if self.elem() == 0 {
+                    self.ip = pos as usize;
                 }
-                Stmt::SetN(n) => {
-                    *self.elem_mut() = Wrapping(n);
+                // this should be a no-op if `profile_collector` does nothing
+                self.profile_collector
+                    .block(self.ip, self.ptr, self.call_stack.len());
+            }
+            Stmt::JmpIfNonZero(pos) => {
+                if self.elem() != 0 {
+                    self.ip = pos as usize;
                 }
-                Stmt::JmpIfZero(pos) => {
-                    if self.elem() == 0 {
-                        self.ip = pos as usize;
-                    }
+                self.profile_collector
+                    .block(self.ip, self.ptr, self.call_stack.len());
+            }
+            Stmt::DebugDump => self.debug_dump(),
+            Stmt::DefineProc(after) => {
+                // the body starts right after this statement
+                self.procedures[usize::from(self.elem())] = self.ip as u32;
+                self.ip = after as usize;
+                self.profile_collector
+                    .block(self.ip, self.ptr, self.call_stack.len());
+            }
+            Stmt::Call => {
+                // calling a procedure that isn't defined does nothing
+                let start = self.procedures[usize::from(self.elem())];
+                if start != NO_PROC {
+                    self.call_stack.push(self.ip);
+                    self.ip = start as usize;
                 }
-                Stmt::JmpIfNonZero(pos) => {
-                    if self.elem() != 0 {
-                        self.ip = pos as usize;
-                    }
+                self.profile_collector
+                    .block(self.ip, self.ptr, self.call_stack.len());
+            }
+            Stmt::Return => {
+                // verified code only reaches the body of a procedure with a `Call`, but a restored
+                // `Vm` might be anywhere, so returning from nowhere just goes on
+                if let Some(ip) = self.call_stack.pop() {
+                    self.ip = ip;
                 }
-                Stmt::End => break,
+                self.profile_collector
+                    .block(self.ip, self.ptr, self.call_stack.len());
             }
-
-            // this should be a no-op if `profile_collector` is does nothing
-            (self.profile_collector)(self.ip);
+            Stmt::End => return false,
         }
+        true
+    }
+
+    /// Prints the pointer and the 16 cells around it to stderr, the current one in brackets
+    #[cold]
+    fn debug_dump(&self) {
+        let start = self.ptr.saturating_sub(8).min(MEM_SIZE - 16);
+        let cells = (start..start + 16)
+            .map(|idx| match idx == self.ptr {
+                true => format!("[{}]", self.cell(idx)),
+                false => self.cell(idx).to_string(),
+            })
+            .collect::<Vec<_>>();
+        eprintln!(
+            "# ptr {}, cells from {start}: {}",
+            self.ptr,
+            cells.join(" ")
+        );
+    }
+
+    /// Tells the watcher that the statement that is currently executed wrote to the cell at `idx`.
+    /// For `()`, the compiler removes this and the reads of `old` before the write.
+    #[inline(always)]
+    fn watch(&mut self, idx: usize, old: u8) {
+        let new = self.mem[idx].0;
+        self.watcher.write(self.ip - 1, self.ptr, idx, old, new);
+    }
+
+    /// The index of the cell `offset` cells away, wrapping around the ends of the tape. Nothing
+    /// bounds the offsets, the tape might be left by a move that was merged into them.
+    fn offset_idx(&self, offset: i32) -> usize {
+        self.moved_idx(i64::from(offset))
+    }
+
+    /// The index of the cell `distance` cells away, wrapping around the ends of the tape like
+    /// moves do
+    fn moved_idx(&self, distance: i64) -> usize {
+        // a division by a constant, so this is cheap, and lets the compiler drop bounds checks
+        (self.ptr as i64 + distance).rem_euclid(MEM_SIZE as i64) as usize
     }
 
     fn elem_mut_offset(&mut self, offset: i32) -> &mut Wrapping<u8> {
-        let ptr = self.ptr as isize;
-        let offset = offset as isize;
-        // SAFETY: `self.ptr` is never out of bounds
-        debug_assert!(self.ptr < self.mem.len());
-        unsafe { self.mem.get_unchecked_mut((ptr + offset) as usize) }
+        let idx = self.offset_idx(offset);
+        &mut self.mem[idx]
     }
 
     fn elem_mut(&mut self) -> &mut Wrapping<u8> {
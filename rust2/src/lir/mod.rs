@@ -15,27 +15,40 @@
 //! a needless indirection.
 //!
 //! this module must not produce out of bounds jumps and always put the `End` instruction at the
-//! end
+//! end, see [`Lir::verify`]
 
+pub mod bytecode;
 pub mod interpreter;
+mod verify;
 
-use std::fmt::{Debug, Formatter};
+use std::{
+    fmt::{Debug, Formatter},
+    ops::Range,
+};
 
-use bumpalo::Bump;
+pub use verify::VerifyError;
 
 use crate::{
     hir::{Hir, Stmt as HirStmt, StmtKind as HirStmtKind},
+    lir::interpreter::MEM_SIZE,
     parse::Span,
-    BumpVec,
 };
 
 #[derive(Debug, Clone, Copy)]
 pub enum Stmt {
     Add(u8),
     Sub(u8),
-    AddOffset { offset: i32, n: u8 },
-    SubOffset { offset: i32, n: u8 },
-    MoveAddTo { offset: i32 },
+    AddOffset {
+        offset: i32,
+        n: u8,
+    },
+    SubOffset {
+        offset: i32,
+        n: u8,
+    },
+    MoveAddTo {
+        offset: i32,
+    },
     Right(u32),
     Left(u32),
     Out,
@@ -43,24 +56,32 @@ pub enum Stmt {
     SetN(u8),
     JmpIfZero(u32),
     JmpIfNonZero(u32),
+    DebugDump,
+    /// Defines the procedure with the number of the current cell, starting at the next
+    /// statement, and jumps over its body to the position
+    DefineProc(u32),
+    /// Jumps to the procedure with the number of the current cell
+    Call,
+    /// Jumps back to the statement after the last `Call`
+    Return,
     End,
 }
 
 const _: [(); 8] = [(); std::mem::size_of::<Stmt>()];
 
 #[derive(Clone)]
-pub struct Lir<'lir> {
-    stmts: BumpVec<'lir, Stmt>,
-    debug: BumpVec<'lir, Span>,
+pub struct Lir {
+    stmts: Vec<Stmt>,
+    debug: Vec<Span>,
 }
 
-impl Debug for Lir<'_> {
+impl Debug for Lir {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         self.stmts.fmt(f)
     }
 }
 
-impl Lir<'_> {
+impl Lir {
     pub fn stmts(&self) -> &[Stmt] {
         &self.stmts
     }
@@ -68,47 +89,162 @@ impl Lir<'_> {
     pub fn debug(&self) -> &[Span] {
         &self.debug
     }
+
+    /// The source code of the statement. Loop jumps are located at their bracket instead of the
+    /// whole loop.
+    pub fn location(&self, ip: usize) -> Range<usize> {
+        let span = self.debug[ip];
+        match self.stmts[ip] {
+            Stmt::JmpIfZero(_) | Stmt::DefineProc(_) => span.start()..span.start() + 1,
+            Stmt::JmpIfNonZero(_) | Stmt::Return => span.end() - 1..span.end(),
+            _ => span.start()..span.end(),
+        }
+    }
+
+    /// A hash of the statements, which stays the same across processes and versions of this
+    /// crate, as long as the code is compiled to the same statements
+    pub fn fingerprint(&self) -> u64 {
+        // FNV-1a
+        self.stmts
+            .iter()
+            .flat_map(|stmt| stmt.encode())
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+}
+
+impl Stmt {
+    /// The length of [`Stmt::encode`]
+    pub(crate) const ENCODED_LEN: usize = 6;
+
+    /// A stable encoding of the statement: the kind, a byte operand, and a 32 bit operand in
+    /// little endian
+    pub(crate) fn encode(self) -> [u8; Self::ENCODED_LEN] {
+        let (kind, n, arg) = match self {
+            Stmt::Add(n) => (0, n, 0),
+            Stmt::Sub(n) => (1, n, 0),
+            Stmt::AddOffset { offset, n } => (2, n, offset as u32),
+            Stmt::SubOffset { offset, n } => (3, n, offset as u32),
+            Stmt::MoveAddTo { offset } => (4, 0, offset as u32),
+            Stmt::Right(n) => (5, 0, n),
+            Stmt::Left(n) => (6, 0, n),
+            Stmt::Out => (7, 0, 0),
+            Stmt::In => (8, 0, 0),
+            Stmt::SetN(n) => (9, n, 0),
+            Stmt::JmpIfZero(pos) => (10, 0, pos),
+            Stmt::JmpIfNonZero(pos) => (11, 0, pos),
+            Stmt::DebugDump => (12, 0, 0),
+            Stmt::DefineProc(after) => (13, 0, after),
+            Stmt::Call => (14, 0, 0),
+            Stmt::Return => (15, 0, 0),
+            Stmt::End => (16, 0, 0),
+        };
+        let [a, b, c, d] = arg.to_le_bytes();
+        [kind, n, a, b, c, d]
+    }
+
+    /// The statement encoded with [`Stmt::encode`], `None` for an unknown kind
+    pub(crate) fn decode(bytes: [u8; Self::ENCODED_LEN]) -> Option<Self> {
+        let [kind, n, a, b, c, d] = bytes;
+        let arg = u32::from_le_bytes([a, b, c, d]);
+        let offset = arg as i32;
+        Some(match kind {
+            0 => Stmt::Add(n),
+            1 => Stmt::Sub(n),
+            2 => Stmt::AddOffset { offset, n },
+            3 => Stmt::SubOffset { offset, n },
+            4 => Stmt::MoveAddTo { offset },
+            5 => Stmt::Right(arg),
+            6 => Stmt::Left(arg),
+            7 => Stmt::Out,
+            8 => Stmt::In,
+            9 => Stmt::SetN(n),
+            10 => Stmt::JmpIfZero(arg),
+            11 => Stmt::JmpIfNonZero(arg),
+            12 => Stmt::DebugDump,
+            13 => Stmt::DefineProc(arg),
+            14 => Stmt::Call,
+            15 => Stmt::Return,
+            16 => Stmt::End,
+            _ => return None,
+        })
+    }
 }
 
-pub fn generate<'lir>(alloc: &'lir Bump, ir: &Hir<'_>) -> Lir<'lir> {
-    let stmts = Vec::new_in(alloc);
-    let debug = Vec::new_in(alloc);
-    let mut lir = Lir { stmts, debug };
+pub fn generate(ir: &Hir<'_>) -> Lir {
+    let mut lir = Lir {
+        stmts: Vec::new(),
+        debug: Vec::new(),
+    };
 
     hir_to_lir(&mut lir, &ir.stmts);
     lir.stmts.push(Stmt::End);
     lir.debug.push(Span::default());
 
-    assert_eq!(lir.stmts.len(), lir.debug.len());
+    if cfg!(debug_assertions) {
+        if let Err(err) = lir.verify() {
+            panic!("generated invalid code: {err}\n{lir:#?}");
+        }
+    }
 
     lir
 }
 
-fn hir_to_lir<'lir>(lir: &mut Lir<'lir>, ir: &[HirStmt<'_>]) {
+/// The pointer wraps around the tape, so whole laps can be dropped from offsets and moves, which
+/// keeps them as short as [`Lir::verify`] wants them
+fn wrap_offset(offset: i32) -> i32 {
+    offset % MEM_SIZE as i32
+}
+
+fn wrap_move(n: usize) -> u32 {
+    (n % MEM_SIZE) as u32
+}
+
+fn hir_to_lir(lir: &mut Lir, ir: &[HirStmt<'_>]) {
     for ir_stmt in ir {
         hir_stmt_to_lir_stmt(lir, ir_stmt);
     }
     debug_assert_eq!(lir.stmts.len(), lir.debug.len());
 }
 
-fn hir_stmt_to_lir_stmt<'lir>(lir: &mut Lir<'lir>, ir_stmt: &HirStmt<'_>) {
+fn hir_stmt_to_lir_stmt(lir: &mut Lir, ir_stmt: &HirStmt<'_>) {
     let stmt = match &ir_stmt.kind {
         HirStmtKind::Add(0, n) => Stmt::Add(*n),
         HirStmtKind::Sub(0, n) => Stmt::Sub(*n),
         HirStmtKind::Add(offset, n) => Stmt::AddOffset {
-            offset: *offset,
+            offset: wrap_offset(*offset),
             n: *n,
         },
         HirStmtKind::Sub(offset, n) => Stmt::SubOffset {
-            offset: *offset,
+            offset: wrap_offset(*offset),
             n: *n,
         },
-        HirStmtKind::MoveAddTo { offset } => Stmt::MoveAddTo { offset: *offset },
-        HirStmtKind::Right(n) => Stmt::Right(u32::try_from(*n).unwrap()),
-        HirStmtKind::Left(n) => Stmt::Left(u32::try_from(*n).unwrap()),
+        HirStmtKind::MoveAddTo { offset } => Stmt::MoveAddTo {
+            offset: wrap_offset(*offset),
+        },
+        HirStmtKind::Right(n) => Stmt::Right(wrap_move(*n)),
+        HirStmtKind::Left(n) => Stmt::Left(wrap_move(*n)),
         HirStmtKind::Out => Stmt::Out,
         HirStmtKind::In => Stmt::In,
         HirStmtKind::SetN(n) => Stmt::SetN(*n),
+        HirStmtKind::DebugDump => Stmt::DebugDump,
+        HirStmtKind::Call => Stmt::Call,
+        HirStmtKind::Procedure(body) => {
+            let define_idx = lir.stmts.len();
+            lir.stmts.push(Stmt::DefineProc(0)); // placeholder
+            lir.debug.push(ir_stmt.span);
+
+            hir_to_lir(lir, &body.stmts);
+            lir.stmts.push(Stmt::Return);
+            lir.debug.push(ir_stmt.span);
+
+            // there will always at least be an `End` instruction after the procedure
+            let after_proc_idx = lir.stmts.len();
+            lir.stmts[define_idx] = Stmt::DefineProc(after_proc_idx.try_into().unwrap());
+
+            return;
+        }
         HirStmtKind::Loop(instr) => {
             let skip_jmp_idx = lir.stmts.len();
             lir.stmts.push(Stmt::JmpIfZero(0)); // placeholder
@@ -0,0 +1,238 @@
+//! checking the invariants that the interpreter relies on
+//!
+//! The interpreter follows jumps without bounds checks, so code that wasn't just generated by
+//! [`generate`](super::generate) must be verified before it runs. Generated code is verified in
+//! debug builds, to catch bugs in the codegen.
+
+use std::fmt::{Display, Formatter};
+
+use super::{interpreter::MEM_SIZE, Lir, Stmt};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// there isn't a span for every statement
+    DebugLen { stmts: usize, debug: usize },
+    /// the code doesn't end with the only `End`
+    End { ip: usize },
+    /// the statement at `ip` jumps out of the code
+    JumpOutOfBounds { ip: usize },
+    /// the loop or procedure jump at `ip` doesn't match up with its counterpart
+    Unpaired { ip: usize },
+    /// the statement at `ip` moves the pointer or reaches a cell further than the tape is long
+    Offset { ip: usize },
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::DebugLen { stmts, debug } => {
+                write!(f, "{stmts} statements, but {debug} spans")
+            }
+            VerifyError::End { ip } => write!(f, "unexpected end of the code at {ip}"),
+            VerifyError::JumpOutOfBounds { ip } => write!(f, "jump out of the code at {ip}"),
+            VerifyError::Unpaired { ip } => write!(f, "unpaired jump at {ip}"),
+            VerifyError::Offset { ip } => write!(f, "offset outside of the tape at {ip}"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// A loop or procedure that has been entered, but not left yet
+enum Open {
+    /// the `JmpIfZero` at `ip`, jumping to `after`
+    Loop { ip: usize, after: usize },
+    /// the `DefineProc` at `ip`, jumping over the body to `after`
+    Proc { ip: usize, after: usize },
+}
+
+impl Lir {
+    /// Checks that the code can't make the interpreter go out of bounds:
+    ///
+    /// * every statement has a span
+    /// * loops and procedures are nested, and their jumps point at each other
+    /// * every jump lands in the code
+    /// * the code ends with an `End`, and there is no other one
+    /// * no move or offset is longer than the tape, offsets that leave it wrap around
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        if self.stmts.len() != self.debug.len() {
+            return Err(VerifyError::DebugLen {
+                stmts: self.stmts.len(),
+                debug: self.debug.len(),
+            });
+        }
+
+        let len = self.stmts.len();
+        let target = |ip: usize, target: u32| match target as usize {
+            target if target < len => Ok(target),
+            _ => Err(VerifyError::JumpOutOfBounds { ip }),
+        };
+        let in_tape = |ip: usize, distance: u64| match distance < MEM_SIZE as u64 {
+            true => Ok(()),
+            false => Err(VerifyError::Offset { ip }),
+        };
+
+        let mut open = Vec::new();
+        for (ip, stmt) in self.stmts.iter().enumerate() {
+            match *stmt {
+                Stmt::AddOffset { offset, .. }
+                | Stmt::SubOffset { offset, .. }
+                | Stmt::MoveAddTo { offset } => in_tape(ip, u64::from(offset.unsigned_abs()))?,
+                Stmt::Right(n) | Stmt::Left(n) => in_tape(ip, u64::from(n))?,
+                Stmt::JmpIfZero(after) => open.push(Open::Loop {
+                    ip,
+                    after: target(ip, after)?,
+                }),
+                Stmt::DefineProc(after) => open.push(Open::Proc {
+                    ip,
+                    after: target(ip, after)?,
+                }),
+                Stmt::JmpIfNonZero(body) => {
+                    let body = target(ip, body)?;
+                    match open.pop() {
+                        Some(Open::Loop { ip: start, after })
+                            if body == start + 1 && after == ip + 1 => {}
+                        _ => return Err(VerifyError::Unpaired { ip }),
+                    }
+                }
+                Stmt::Return => match open.pop() {
+                    Some(Open::Proc { after, .. }) if after == ip + 1 => {}
+                    _ => return Err(VerifyError::Unpaired { ip }),
+                },
+                Stmt::End if ip != len - 1 => return Err(VerifyError::End { ip }),
+                Stmt::Add(_)
+                | Stmt::Sub(_)
+                | Stmt::Out
+                | Stmt::In
+                | Stmt::SetN(_)
+                | Stmt::DebugDump
+                | Stmt::Call
+                | Stmt::End => {}
+            }
+        }
+
+        if let Some(Open::Loop { ip, .. } | Open::Proc { ip, .. }) = open.pop() {
+            return Err(VerifyError::Unpaired { ip });
+        }
+        match self.stmts.last() {
+            Some(Stmt::End) => Ok(()),
+            _ => Err(VerifyError::End { ip: len }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VerifyError;
+    use crate::{
+        lir::{self, interpreter::MEM_SIZE, Lir, Stmt},
+        parse::Span,
+        CompileOptions, Program,
+    };
+
+    fn lir(stmts: &[Stmt]) -> Lir {
+        Lir {
+            stmts: stmts.to_vec(),
+            debug: vec![Span::default(); stmts.len()],
+        }
+    }
+
+    #[test]
+    fn generated_code() {
+        let options = CompileOptions {
+            dialect: "pbrain,debug".parse().unwrap(),
+            ..CompileOptions::default()
+        };
+        for src in [
+            include_str!("../../benches/fizzbuzz.bf"),
+            include_str!("../../benches/mandelbrot.bf"),
+            "+(>[-]<[>+<-]):[]#",
+        ] {
+            let program = Program::compile(src, &options).unwrap();
+            assert_eq!(program.lir().verify(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn long_moves() {
+        let options = CompileOptions::default();
+        let src = format!(
+            "+{}.>>{}+.",
+            "<".repeat(MEM_SIZE + 2),
+            ">".repeat(3 * MEM_SIZE)
+        );
+        let program = Program::compile(&src, &options).unwrap();
+        assert_eq!(program.lir().verify(), Ok(()));
+
+        let mut bytecode = Vec::new();
+        program.write_bytecode(&mut bytecode).unwrap();
+        let loaded = Program::from_bytecode(bytecode.as_slice()).unwrap();
+        let mut output = Vec::new();
+        loaded.run(&Default::default(), std::io::empty(), &mut output);
+        assert_eq!(output, [0, 2]);
+    }
+
+    #[test]
+    fn offsets_wrap_around() {
+        use Stmt::*;
+
+        let code = lir(&[
+            AddOffset { offset: -1, n: 1 },
+            Right(1),
+            AddOffset { offset: -5, n: 2 },
+            SubOffset {
+                offset: MEM_SIZE as i32 - 1,
+                n: 3,
+            },
+            End,
+        ]);
+        assert_eq!(code.verify(), Ok(()));
+
+        let state = lir::interpreter::run(&code, std::io::sink(), std::io::empty(), |_| {});
+        assert_eq!(state.mem[MEM_SIZE - 1], 1);
+        assert_eq!(state.mem[MEM_SIZE - 4], 2);
+        assert_eq!(state.mem[0], 253);
+    }
+
+    #[test]
+    fn broken_code() {
+        use Stmt::*;
+
+        let cases = [
+            (vec![Add(1)], VerifyError::End { ip: 1 }),
+            (vec![End, End], VerifyError::End { ip: 0 }),
+            (
+                vec![JmpIfZero(9), End],
+                VerifyError::JumpOutOfBounds { ip: 0 },
+            ),
+            (
+                vec![JmpIfZero(3), Out, JmpIfNonZero(0), End],
+                VerifyError::Unpaired { ip: 2 },
+            ),
+            (vec![JmpIfZero(1), End], VerifyError::Unpaired { ip: 0 }),
+            (
+                vec![DefineProc(3), Out, JmpIfNonZero(1), End],
+                VerifyError::Unpaired { ip: 2 },
+            ),
+            (vec![Return, End], VerifyError::Unpaired { ip: 0 }),
+            (
+                vec![Left(MEM_SIZE as u32), End],
+                VerifyError::Offset { ip: 0 },
+            ),
+            (
+                vec![MoveAddTo { offset: i32::MIN }, End],
+                VerifyError::Offset { ip: 0 },
+            ),
+        ];
+        for (stmts, expected) in cases {
+            assert_eq!(lir(&stmts).verify(), Err(expected), "{stmts:?}");
+        }
+
+        let mut missing_span = lir(&[Out, End]);
+        missing_span.debug.pop();
+        assert_eq!(
+            missing_span.verify(),
+            Err(VerifyError::DebugLen { stmts: 2, debug: 1 })
+        );
+    }
+}
@@ -1,16 +1,20 @@
-#![feature(allocator_api, let_else)]
 #![warn(rust_2018_idioms)]
 
-use std::{fs, io, process};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+    process,
+};
 
-use brainfuck::Args;
+use brainfuck::{Args, Command, EmitKind, InputArgs, Program, RunOptions, Source};
 use clap::Parser;
 
 fn main() {
     let stdout = io::stdout();
     let stdout = stdout.lock();
-    let stdin = io::stdin();
-    let stdin = stdin.lock();
+    // locked only once the program is read, which might come from stdin
+    let stdin = || io::stdin().lock();
 
     let args = Args::parse();
 
@@ -19,13 +23,167 @@ fn main() {
         .without_time()
         .init();
 
-    let src = fs::read_to_string(&args.file).unwrap_or_else(|err| {
-        eprintln!("error: Failed to read file: {err}");
+    let result = match &args.command {
+        Command::Run {
+            source,
+            options,
+            input,
+        } => {
+            if let Some(path) = source.bytecode() {
+                if let Some(option) = options.bytecode_conflict() {
+                    eprintln!("error: {option} can't be used with compiled bytecode");
+                    process::exit(1);
+                }
+                let program = Program::from_bytecode(open(path)).unwrap_or_else(|err| {
+                    eprintln!("error: {err}");
+                    process::exit(1);
+                });
+                let run_options = RunOptions {
+                    watch: options.watch.clone(),
+                    input: read_input(input),
+                };
+                program.run(&run_options, stdin(), stdout);
+                return;
+            }
+
+            let src = read(source);
+            if let Some(path) = &options.trace {
+                let trace = File::create(path).unwrap_or_else(|err| {
+                    eprintln!("error: Failed to create trace file: {err}");
+                    process::exit(1);
+                });
+                let input = program_input(input);
+                brainfuck::trace::record(&src, stdout, input, trace).unwrap_or_else(|err| {
+                    eprintln!("error: {err}");
+                    process::exit(1);
+                });
+                return;
+            }
+            brainfuck::run(&src, stdout, stdin(), read_input(input), options)
+        }
+        Command::Check { source, options } => brainfuck::check(&read(source), options),
+        Command::Dump {
+            level,
+            source,
+            options,
+        } => brainfuck::dump(&read(source), *level, options),
+        Command::Profile {
+            source,
+            options,
+            input,
+        } => {
+            let src = read(source);
+            let input = read_input(input);
+            brainfuck::profile(&src, stdout, stdin(), input, options, &source.name())
+        }
+        Command::Bench {
+            source,
+            options,
+            input,
+            runs,
+        } => {
+            let src = read(source);
+            // the program gets the same input in every run
+            let input = read_input(input).unwrap_or_else(|| {
+                let mut input = Vec::new();
+                if !source.is_stdin() {
+                    stdin().read_to_end(&mut input).unwrap_or_else(|err| {
+                        eprintln!("error: Failed to read input: {err}");
+                        process::exit(1);
+                    });
+                }
+                input
+            });
+            brainfuck::bench(&src, &input, options, *runs)
+        }
+        Command::Fmt { source, width } => {
+            let src = read(source);
+            brainfuck::format::format(&src, *width)
+                .map(|formatted| print!("{formatted}"))
+                .map_err(Into::into)
+        }
+        Command::Minify { source } => {
+            let src = read(source);
+            brainfuck::format::minify(&src)
+                .map(|minified| println!("{minified}"))
+                .map_err(Into::into)
+        }
+        Command::Compile {
+            source,
+            options,
+            emit,
+            output,
+        } => {
+            let src = read(source);
+            let output = match (output, emit, &source.file) {
+                (Some(output), ..) => Some(output.clone()),
+                (None, EmitKind::Bytecode, Some(file))
+                    if source.eval.is_none() && !source.is_stdin() =>
+                {
+                    Some(file.with_extension("bfc"))
+                }
+                _ => None,
+            };
+            match output {
+                Some(path) => {
+                    let out = File::create(&path).unwrap_or_else(|err| {
+                        eprintln!("error: Failed to create {}: {err}", path.display());
+                        process::exit(1);
+                    });
+                    brainfuck::emit(&src, *emit, options, out)
+                }
+                None => brainfuck::emit(&src, *emit, options, stdout),
+            }
+        }
+        Command::Debug { source, options } => {
+            let src = read(source);
+            brainfuck::debugger::debug(&src, &options.options(), stdout, stdin(), io::stderr())
+        }
+        Command::Replay { trace } => {
+            brainfuck::trace::replay(BufReader::new(open(trace)), stdout).unwrap_or_else(|err| {
+                eprintln!("error: {err}");
+                process::exit(1);
+            });
+            return;
+        }
+        Command::GenText { text } => {
+            println!("{}", brainfuck::textgen::generate(text.as_bytes()));
+            return;
+        }
+    };
+
+    result.unwrap_or_else(|err| {
+        eprintln!("error: {err}");
         process::exit(1);
     });
+}
 
-    brainfuck::run(&src, stdout, stdin, &args).unwrap_or_else(|_| {
-        eprintln!("error: Failed to parse brainfuck code");
+fn read(source: &Source) -> String {
+    source.read().unwrap_or_else(|err| {
+        eprintln!("error: Failed to read program: {err}");
         process::exit(1);
-    });
+    })
+}
+
+/// The input from `--input` or `--input-string`, if there is any
+fn read_input(input: &InputArgs) -> Option<Vec<u8>> {
+    input.read().unwrap_or_else(|err| {
+        eprintln!("error: Failed to read input: {err}");
+        process::exit(1);
+    })
+}
+
+/// The input from `--input` or `--input-string`, or stdin
+fn program_input(input: &InputArgs) -> Box<dyn Read> {
+    match read_input(input) {
+        Some(input) => Box::new(io::Cursor::new(input)),
+        None => Box::new(io::stdin().lock()),
+    }
+}
+
+fn open(path: &Path) -> File {
+    File::open(path).unwrap_or_else(|err| {
+        eprintln!("error: Failed to read file: {err}");
+        process::exit(1);
+    })
 }
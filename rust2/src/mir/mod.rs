@@ -72,6 +72,9 @@ enum StmtKind<'mir> {
     Out,
     In(Store),
     SetN(u8, Store),
+    DebugDump,
+    Procedure(Mir<'mir>),
+    Call,
 }
 
 #[tracing::instrument(skip(alloc, hir))]
@@ -83,7 +86,7 @@ pub fn optimized_mir<'mir>(alloc: &'mir Bump, hir: &Hir<'_>) -> Mir<'mir> {
 
 /// compiles hir down to a minimal mir
 fn hir_to_mir<'mir>(alloc: &'mir Bump, hir: &Hir<'_>) -> Mir<'mir> {
-    let mut stmts = Vec::new_in(alloc);
+    let mut stmts = BumpVec::new_in(alloc);
     let iter = hir.stmts.iter().map(|hir_stmt| {
         let kind = match *hir_stmt.kind() {
             HirStmtKind::Add(offset, n) => StmtKind::AddSub {
@@ -107,6 +110,9 @@ fn hir_to_mir<'mir>(alloc: &'mir Bump, hir: &Hir<'_>) -> Mir<'mir> {
             HirStmtKind::Out => StmtKind::Out,
             HirStmtKind::In => StmtKind::In(Store::dead()),
             HirStmtKind::SetN(n) => StmtKind::SetN(n, Store::dead()),
+            HirStmtKind::DebugDump => StmtKind::DebugDump,
+            HirStmtKind::Procedure(ref body) => StmtKind::Procedure(hir_to_mir(alloc, body)),
+            HirStmtKind::Call => StmtKind::Call,
         };
         Stmt {
             kind,
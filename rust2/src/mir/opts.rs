@@ -84,7 +84,14 @@ fn pass_fill_state_info_inner<'mir>(
                     },
                 )
             }
-            StmtKind::Out => outer,
+            StmtKind::Out | StmtKind::DebugDump => outer,
+            StmtKind::Procedure(body) => {
+                // the body runs whenever it's called, where we don't know anything
+                pass_fill_state_info_inner(alloc, body, MemoryState::empty(alloc));
+                outer
+            }
+            // forget all knowledge, the procedure might have touched it all and moved the pointer
+            StmtKind::Call => MemoryState::single(alloc, outer, MemoryStateChange::Forget),
             StmtKind::In(store) => MemoryState::single(
                 alloc,
                 outer,
@@ -170,12 +177,24 @@ fn pass_dead_store_elimination_mark_dead_stores(mir: &Mir<'_>) {
 
                 pass_dead_store_elimination_mark_dead_stores(body);
             }
-            StmtKind::Out => {
+            StmtKind::Out | StmtKind::Call => {
                 let store = potential_dead_stores.get(&current_offset);
                 if let Some(store) = store {
                     store.add_load();
                 }
             }
+            StmtKind::Procedure(body) => {
+                let store = potential_dead_stores.get(&current_offset);
+                if let Some(store) = store {
+                    store.add_load();
+                }
+
+                pass_dead_store_elimination_mark_dead_stores(body);
+            }
+            StmtKind::DebugDump => {
+                // it reads all the cells
+                potential_dead_stores.values().for_each(Store::clobber);
+            }
             StmtKind::In(store) | StmtKind::SetN(_, store) => {
                 mark_store(&mut potential_dead_stores, current_offset, store);
             }
@@ -209,6 +228,7 @@ fn pass_const_propagation_inner(mir: &mut Mir<'_>) {
                 // as dead.
                 pass_const_propagation_inner(body);
             }
+            StmtKind::Procedure(body) => pass_const_propagation_inner(body),
             _ => {}
         }
     }
@@ -49,7 +49,7 @@ pub struct MemoryState<'mir>(Rc<RefCell<MemoryStateInner<'mir>>>);
 
 impl<'mir> MemoryState<'mir> {
     pub fn empty(alloc: &'mir Bump) -> Self {
-        Self::new(None, Vec::new_in(alloc))
+        Self::new(None, BumpVec::new_in(alloc))
     }
 
     pub fn single(
@@ -57,7 +57,7 @@ impl<'mir> MemoryState<'mir> {
         prev: MemoryState<'mir>,
         delta: MemoryStateChange,
     ) -> MemoryState<'mir> {
-        let mut deltas = Vec::new_in(alloc);
+        let mut deltas = BumpVec::new_in(alloc);
         deltas.push(delta);
         Self::new(Some(prev), deltas)
     }
@@ -68,7 +68,7 @@ impl<'mir> MemoryState<'mir> {
         delta1: MemoryStateChange,
         delta2: MemoryStateChange,
     ) -> MemoryState<'mir> {
-        let mut deltas = Vec::new_in(alloc);
+        let mut deltas = BumpVec::new_in(alloc);
         deltas.push(delta1);
         deltas.push(delta2);
         Self::new(Some(prev), deltas)
@@ -1,10 +1,14 @@
 use std::{
     cmp,
     fmt::{Debug, Formatter},
+    iter::Peekable,
+    str::FromStr,
 };
 
 use bumpalo::Bump;
 
+use crate::BumpVec;
+
 #[derive(Clone, Copy, PartialEq, Eq, Default)]
 pub struct Span {
     start: u32,
@@ -20,21 +24,13 @@ impl Span {
     }
 
     /// start..end
-    fn start_end(start: usize, end: usize) -> Span {
+    pub(crate) fn start_end(start: usize, end: usize) -> Span {
         Self {
             start: start.try_into().unwrap(),
             len: (end - start).try_into().unwrap(),
         }
     }
 
-    // start..=end
-    fn start_end_incl(start: usize, end: usize) -> Span {
-        Self {
-            start: start.try_into().unwrap(),
-            len: (end - start + 1).try_into().unwrap(),
-        }
-    }
-
     #[must_use]
     pub fn until(&self, other: Self) -> Self {
         Self {
@@ -55,6 +51,8 @@ impl Span {
         self.start.try_into().unwrap()
     }
 
+    // a span is a range of the source, not a collection that would be empty
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         self.len.try_into().unwrap()
     }
@@ -71,7 +69,7 @@ impl Debug for Span {
     }
 }
 
-pub type Ast<'ast> = Vec<(Instr<'ast>, Span), &'ast Bump>;
+pub type Ast<'ast> = BumpVec<'ast, (Instr<'ast>, Span)>;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instr<'ast> {
@@ -82,46 +80,120 @@ pub enum Instr<'ast> {
     Out,
     In,
     Loop(Ast<'ast>),
+    /// `#`, prints the pointer and the cells around it to stderr
+    DebugDump,
+    /// `(...)`, defines the procedure with the number of the current cell
+    Procedure(Ast<'ast>),
+    /// `:`, calls the procedure with the number of the current cell
+    Call,
+    /// `+`, `-`, `>` or `<` followed by how often it's repeated, like `+200`
+    Repeat(Repeatable, u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repeatable {
+    Add,
+    Sub,
+    Right,
+    Left,
+}
+
+/// The extensions to the classic 8 commands that are enabled. By default, there are none, and
+/// all other characters are comments.
+///
+/// Parsed from a comma separated list of `debug`, `input`, `pbrain` and `rle`, or `classic` for
+/// none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Dialect {
+    /// `#` dumps the tape around the pointer to stderr
+    pub debug_dump: bool,
+    /// `!` ends the code, everything after it is the input of the program
+    pub inline_input: bool,
+    /// pbrain procedures with `(`, `)` and `:`. Calling a procedure that isn't defined does
+    /// nothing.
+    pub procedures: bool,
+    /// `+`, `-`, `>` and `<` can be followed by a repeat count, `+200>3` is 200 `+` and 3 `>`
+    pub rle: bool,
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut dialect = Self::default();
+        for extension in s.split(',') {
+            match extension.trim() {
+                "classic" => {}
+                "debug" => dialect.debug_dump = true,
+                "input" => dialect.inline_input = true,
+                "pbrain" => dialect.procedures = true,
+                "rle" => dialect.rle = true,
+                other => return Err(format!("Invalid dialect extension: '{other}'")),
+            }
+        }
+        Ok(dialect)
+    }
+}
+
+/// Splits `src` at the first `!` into the code and the input after it, for
+/// [`Dialect::inline_input`]
+pub fn split_inline_input(src: &str) -> (&str, Option<&str>) {
+    match src.split_once('!') {
+        Some((code, input)) => (code, Some(input)),
+        None => (src, None),
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ParseError;
 
-pub fn parse<I>(alloc: &Bump, mut src: I) -> Result<Ast<'_>, ParseError>
+/// Parses the classic 8 commands
+pub fn parse<I>(alloc: &Bump, src: I) -> Result<Ast<'_>, ParseError>
 where
     I: Iterator<Item = (usize, u8)>,
 {
-    let mut instrs = Vec::new_in(alloc);
-
-    loop {
-        match src.next() {
-            Some((idx, b'+')) => instrs.push((Instr::Add, Span::single(idx))),
-            Some((idx, b'-')) => instrs.push((Instr::Sub, Span::single(idx))),
-            Some((idx, b'>')) => instrs.push((Instr::Right, Span::single(idx))),
-            Some((idx, b'<')) => instrs.push((Instr::Left, Span::single(idx))),
-            Some((idx, b'.')) => instrs.push((Instr::Out, Span::single(idx))),
-            Some((idx, b',')) => instrs.push((Instr::In, Span::single(idx))),
-            Some((idx, b'[')) => {
-                let (loop_instrs, span) = parse_loop(alloc, &mut src, 0, idx)?;
-                instrs.push((Instr::Loop(loop_instrs), span));
-            }
-            Some((_, b']')) => return Err(ParseError),
-            Some(_) => {} // comment
-            None => break,
-        }
-    }
+    parse_dialect(alloc, src, Dialect::default())
+}
+
+pub fn parse_dialect<I>(alloc: &Bump, src: I, dialect: Dialect) -> Result<Ast<'_>, ParseError>
+where
+    I: Iterator<Item = (usize, u8)>,
+{
+    let tokens = src.map(|(idx, byte)| (Span::single(idx), byte));
+    parse_tokens(alloc, tokens, dialect)
+}
 
-    Ok(instrs)
+/// Parses tokens that are already mapped to the brainfuck command they stand for, with their
+/// span in the source. Used for languages that are brainfuck with other tokens.
+pub(crate) fn parse_tokens<I>(
+    alloc: &Bump,
+    tokens: I,
+    dialect: Dialect,
+) -> Result<Ast<'_>, ParseError>
+where
+    I: Iterator<Item = (Span, u8)>,
+{
+    let mut tokens = tokens.peekable();
+    parse_block(alloc, &mut tokens, dialect, Block::TopLevel, 0).map(|(instrs, _)| instrs)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Block {
+    TopLevel,
+    Loop,
+    Procedure,
 }
 
-fn parse_loop<'ast, I>(
+/// Parses until the end of the block, returning the span of its closing bracket
+fn parse_block<'ast, I>(
     alloc: &'ast Bump,
-    src: &mut I,
+    src: &mut Peekable<I>,
+    dialect: Dialect,
+    block: Block,
     depth: u16,
-    start_idx: usize,
-) -> Result<(Ast<'ast>, Span), ParseError>
+) -> Result<(Ast<'ast>, Option<Span>), ParseError>
 where
-    I: Iterator<Item = (usize, u8)>,
+    I: Iterator<Item = (Span, u8)>,
 {
     const MAX_DEPTH: u16 = 1000;
 
@@ -129,27 +201,97 @@ where
         return Err(ParseError);
     }
 
-    let mut instrs = Vec::new_in(alloc);
+    let mut instrs = BumpVec::new_in(alloc);
 
-    let end_idx = loop {
-        match src.next() {
-            Some((idx, b'+')) => instrs.push((Instr::Add, Span::single(idx))),
-            Some((idx, b'-')) => instrs.push((Instr::Sub, Span::single(idx))),
-            Some((idx, b'>')) => instrs.push((Instr::Right, Span::single(idx))),
-            Some((idx, b'<')) => instrs.push((Instr::Left, Span::single(idx))),
-            Some((idx, b'.')) => instrs.push((Instr::Out, Span::single(idx))),
-            Some((idx, b',')) => instrs.push((Instr::In, Span::single(idx))),
-            Some((idx, b'[')) => {
-                let (loop_instrs, span) = parse_loop(alloc, src, depth + 1, idx)?;
-                instrs.push((Instr::Loop(loop_instrs), span));
+    let end = loop {
+        let Some((span, byte)) = src.next() else {
+            match block {
+                Block::TopLevel => break None,
+                _ => return Err(ParseError),
             }
-            Some((idx, b']')) => break idx,
-            Some(_) => {} // comment
-            None => return Err(ParseError),
-        }
+        };
+
+        let instr = match byte {
+            b'+' | b'-' | b'>' | b'<'
+                if dialect.rle && matches!(src.peek(), Some((_, b'0'..=b'9'))) =>
+            {
+                let repeatable = match byte {
+                    b'+' => Repeatable::Add,
+                    b'-' => Repeatable::Sub,
+                    b'>' => Repeatable::Right,
+                    _ => Repeatable::Left,
+                };
+                let (count, end) = parse_count(src)?;
+                instrs.push((Instr::Repeat(repeatable, count), span.merge(end)));
+                continue;
+            }
+            b'+' => Instr::Add,
+            b'-' => Instr::Sub,
+            b'>' => Instr::Right,
+            b'<' => Instr::Left,
+            b'.' => Instr::Out,
+            b',' => Instr::In,
+            b'[' => {
+                let (body, span) = parse_nested(alloc, src, dialect, Block::Loop, depth, span)?;
+                instrs.push((Instr::Loop(body), span));
+                continue;
+            }
+            b']' if block == Block::Loop => break Some(span),
+            b']' => return Err(ParseError),
+            b'#' if dialect.debug_dump => Instr::DebugDump,
+            b'!' if dialect.inline_input => match block {
+                Block::TopLevel => break None,
+                _ => return Err(ParseError),
+            },
+            b'(' if dialect.procedures => {
+                let (body, span) =
+                    parse_nested(alloc, src, dialect, Block::Procedure, depth, span)?;
+                instrs.push((Instr::Procedure(body), span));
+                continue;
+            }
+            b')' if dialect.procedures && block == Block::Procedure => break Some(span),
+            b')' if dialect.procedures => return Err(ParseError),
+            b':' if dialect.procedures => Instr::Call,
+            _ => continue, // comment
+        };
+        instrs.push((instr, span));
     };
 
-    Ok((instrs, Span::start_end_incl(start_idx, end_idx)))
+    Ok((instrs, end))
+}
+
+/// Parses the digits of a repeat count, returning it and the span of its last digit
+fn parse_count<I>(src: &mut Peekable<I>) -> Result<(u32, Span), ParseError>
+where
+    I: Iterator<Item = (Span, u8)>,
+{
+    let mut count = 0u32;
+    let mut end = Span::default();
+    while let Some(&(span, digit @ b'0'..=b'9')) = src.peek() {
+        count = count
+            .checked_mul(10)
+            .and_then(|count| count.checked_add(u32::from(digit - b'0')))
+            .ok_or(ParseError)?;
+        end = span;
+        src.next();
+    }
+    Ok((count, end))
+}
+
+fn parse_nested<'ast, I>(
+    alloc: &'ast Bump,
+    src: &mut Peekable<I>,
+    dialect: Dialect,
+    block: Block,
+    depth: u16,
+    start: Span,
+) -> Result<(Ast<'ast>, Span), ParseError>
+where
+    I: Iterator<Item = (Span, u8)>,
+{
+    let (instrs, end) = parse_block(alloc, src, dialect, block, depth + 1)?;
+    let end = end.expect("nested blocks end with their closing bracket");
+    Ok((instrs, start.merge(end)))
 }
 
 #[cfg(test)]
@@ -173,4 +315,35 @@ mod tests {
         let instrs = super::parse(&alloc, bf.bytes().enumerate());
         insta::assert_debug_snapshot!(instrs);
     }
+
+    #[test]
+    fn dialect() {
+        let alloc = Bump::new();
+
+        let dialect = "debug,input,pbrain".parse().unwrap();
+        let bf = "+(#[-]):!,.";
+        let instrs = super::parse_dialect(&alloc, bf.bytes().enumerate(), dialect);
+        insta::assert_debug_snapshot!(instrs);
+    }
+
+    #[test]
+    fn dialect_mismatched_brackets() {
+        let alloc = Bump::new();
+
+        let dialect = "input,pbrain".parse().unwrap();
+        for bf in ["([)]", "[(])", "(", ")", "[!]"] {
+            let instrs = super::parse_dialect(&alloc, bf.bytes().enumerate(), dialect);
+            assert_eq!(instrs, Err(super::ParseError), "{bf}");
+        }
+    }
+
+    #[test]
+    fn rle() {
+        let alloc = Bump::new();
+
+        let dialect = "rle".parse().unwrap();
+        let bf = "+200>3[-]<.5";
+        let instrs = super::parse_dialect(&alloc, bf.bytes().enumerate(), dialect);
+        insta::assert_debug_snapshot!(instrs);
+    }
 }
@@ -0,0 +1,632 @@
+//! execution profiles
+//!
+//! The interpreter counts how often every basic block is entered. From these counts, the counts
+//! for every statement, source byte and every loop are derived, which are then either printed as
+//! colored source code or written in a format that other tools understand:
+//!
+//! * `json`: the counts for every source byte and every loop
+//! * `callgrind`: for kcachegrind, every loop is a function that is called by its enclosing loop
+//! * `folded`: folded stacks for flamegraphs, every loop is a frame
+//! * `loops`: a table of all loops, the ones that executed the most statements first
+//!
+//! For `loops` (and in `json`), the [`LoopCollector`] additionally records how many iterations
+//! every loop ran each time it was entered, and whether its iterations moved the pointer.
+
+use std::{
+    fmt::Display,
+    io::{self, Write},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+use owo_colors::OwoColorize;
+
+use crate::{
+    lir::{interpreter::ProfileCollector, Lir, Stmt},
+    parse::Span,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProfileFormat {
+    #[default]
+    Color,
+    Json,
+    Callgrind,
+    Folded,
+    Loops,
+}
+
+impl FromStr for ProfileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "color" => Ok(Self::Color),
+            "json" => Ok(Self::Json),
+            "callgrind" => Ok(Self::Callgrind),
+            "folded" => Ok(Self::Folded),
+            "loops" => Ok(Self::Loops),
+            other => Err(format!("Invalid profile format: '{other}'")),
+        }
+    }
+}
+
+pub struct Profile<'a> {
+    src: &'a str,
+    /// how often every source byte was executed
+    bytes: Vec<u64>,
+    /// the sum of all statement counts
+    total: u64,
+    /// statements outside of any loop
+    top_level: u64,
+    /// in the order of their `[`, so enclosing loops come before their nested loops
+    loops: Vec<LoopProfile>,
+}
+
+pub struct LoopProfile {
+    pub span: Span,
+    /// the index of the innermost enclosing loop
+    pub parent: Option<usize>,
+    /// how often the loop was reached
+    pub entries: u64,
+    /// how often the loop body was run
+    pub iterations: u64,
+    /// statements executed inside the loop, including nested loops
+    pub inclusive: u64,
+    /// statements executed inside the loop, excluding nested loops
+    pub exclusive: u64,
+    /// only there if the profile was collected by a [`LoopCollector`]
+    pub stats: Option<LoopStats>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoopStats {
+    /// how many iterations the loop ran each time it was entered
+    pub iterations: Histogram,
+    /// whether every iteration ended with the pointer where it started
+    pub balanced: bool,
+}
+
+/// Counts values in power of two buckets: `0`, `1`, `2..=3`, `4..=7` and so on
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    /// The ranges of values with how many values were in them, skipping empty buckets
+    pub fn buckets(&self) -> impl Iterator<Item = (RangeInclusive<u64>, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(bucket, &count)| {
+                let range = match bucket {
+                    0 => 0..=0,
+                    _ => 1 << (bucket - 1)..=u64::MAX >> (64 - bucket),
+                };
+                (range, count)
+            })
+    }
+}
+
+/// Counts the entered blocks like a `FnMut(usize)` collector, but also records the
+/// [`LoopStats`] of every loop.
+pub struct LoopCollector {
+    block_counts: Vec<u64>,
+    /// the loop whose body starts at the ip
+    body_starts: Vec<Option<usize>>,
+    /// the loop that is exited at the ip, whether it was skipped or not
+    exits: Vec<Option<usize>>,
+    stats: Vec<LoopStats>,
+    /// the loops that are currently running, innermost last
+    running: Vec<RunningLoop>,
+}
+
+struct RunningLoop {
+    idx: usize,
+    /// the number of procedure calls running when the loop was entered, a recursive call can
+    /// enter the same loop again
+    depth: usize,
+    iterations: u64,
+    /// the pointer at the start of the current iteration
+    start_ptr: usize,
+}
+
+impl LoopCollector {
+    pub fn new(lir: &Lir) -> Self {
+        let stmts = lir.stmts();
+        let mut body_starts = vec![None; stmts.len()];
+        let mut exits = vec![None; stmts.len()];
+        let mut loops = 0;
+        for (ip, stmt) in stmts.iter().enumerate() {
+            if let &Stmt::JmpIfZero(after) = stmt {
+                body_starts[ip + 1] = Some(loops);
+                exits[after as usize] = Some(loops);
+                loops += 1;
+            }
+        }
+
+        Self {
+            block_counts: vec![0; stmts.len()],
+            body_starts,
+            exits,
+            stats: vec![
+                LoopStats {
+                    balanced: true,
+                    ..LoopStats::default()
+                };
+                loops
+            ],
+            running: Vec::new(),
+        }
+    }
+
+    /// Returns the block counts and the stats of every loop, in the order of their `[`
+    pub fn finish(self) -> (Vec<u64>, Vec<LoopStats>) {
+        debug_assert!(self.running.is_empty());
+        (self.block_counts, self.stats)
+    }
+
+    /// The current iteration of the innermost loop ended with the pointer at `ptr`
+    fn end_iteration(&mut self, ptr: usize) {
+        let running = self.running.last_mut().expect("no loop is running");
+        running.iterations += 1;
+        if running.start_ptr != ptr {
+            self.stats[running.idx].balanced = false;
+        }
+        running.start_ptr = ptr;
+    }
+}
+
+impl ProfileCollector for &mut LoopCollector {
+    fn block(&mut self, ip: usize, ptr: usize, depth: usize) {
+        self.block_counts[ip] += 1;
+
+        let innermost = self
+            .running
+            .last()
+            .filter(|running| running.depth == depth)
+            .map(|running| running.idx);
+        if let Some(idx) = self.body_starts[ip] {
+            if innermost == Some(idx) {
+                // jumped back from the end of the loop
+                self.end_iteration(ptr);
+            } else {
+                self.running.push(RunningLoop {
+                    idx,
+                    depth,
+                    iterations: 0,
+                    start_ptr: ptr,
+                });
+            }
+        } else if let Some(idx) = self.exits[ip] {
+            if innermost == Some(idx) {
+                // fell through the end of the loop
+                self.end_iteration(ptr);
+                let running = self.running.pop().unwrap();
+                self.stats[idx].iterations.record(running.iterations);
+            } else {
+                // skipped the loop
+                self.stats[idx].iterations.record(0);
+            }
+        }
+    }
+}
+
+impl<'a> Profile<'a> {
+    /// `block_counts` contains how often every basic block of `lir` was entered, indexed by the
+    /// ip of its first statement, see [`ProfileCollector`]
+    pub fn new(src: &'a str, lir: &Lir, block_counts: &[u64]) -> Self {
+        let stmt_counts = stmt_counts(lir, block_counts);
+
+        let mut bytes = vec![0; src.len()];
+        let mut total = 0;
+        let mut top_level = 0;
+        let mut loops = Vec::<LoopProfile>::new();
+        // indices into `loops`
+        let mut open_loops = Vec::new();
+
+        for (ip, (stmt, &count)) in lir.stmts().iter().zip(&stmt_counts).enumerate() {
+            if let Stmt::End = stmt {
+                break;
+            }
+
+            for byte in &mut bytes[lir.location(ip)] {
+                *byte += count;
+            }
+
+            if let Stmt::JmpIfZero(_) = stmt {
+                open_loops.push(loops.len());
+                loops.push(LoopProfile {
+                    span: lir.debug()[ip],
+                    parent: open_loops.iter().rev().nth(1).copied(),
+                    entries: count,
+                    iterations: 0,
+                    inclusive: 0,
+                    exclusive: 0,
+                    stats: None,
+                });
+            }
+
+            total += count;
+            match open_loops.last() {
+                Some(&innermost) => loops[innermost].exclusive += count,
+                None => top_level += count,
+            }
+            for &open in &open_loops {
+                loops[open].inclusive += count;
+            }
+
+            if let Stmt::JmpIfNonZero(_) = stmt {
+                let innermost = open_loops.pop().expect("unbalanced loop jumps");
+                // the body is run once for every time the end of the loop is reached
+                loops[innermost].iterations = count;
+            }
+        }
+
+        Self {
+            src,
+            bytes,
+            total,
+            top_level,
+            loops,
+        }
+    }
+
+    /// Adds the stats from a [`LoopCollector`] to the loops
+    pub fn set_loop_stats(&mut self, stats: Vec<LoopStats>) {
+        assert_eq!(self.loops.len(), stats.len());
+        for (lp, stats) in self.loops.iter_mut().zip(stats) {
+            lp.stats = Some(stats);
+        }
+    }
+
+    pub fn bytes(&self) -> &[u64] {
+        &self.bytes
+    }
+
+    pub fn loops(&self) -> &[LoopProfile] {
+        &self.loops
+    }
+
+    pub fn write(&self, format: ProfileFormat, file_name: &str, out: impl Write) -> io::Result<()> {
+        match format {
+            ProfileFormat::Color => self.write_colored(out),
+            ProfileFormat::Json => self.write_json(out),
+            ProfileFormat::Callgrind => self.write_callgrind(file_name, out),
+            ProfileFormat::Folded => self.write_folded(out),
+            ProfileFormat::Loops => self.write_loops(out),
+        }
+    }
+
+    pub fn write_colored(&self, mut out: impl Write) -> io::Result<()> {
+        let max = self.bytes.iter().max().copied().unwrap_or(0);
+        writeln!(out, "\n\n---------------- Profile ----------------")?;
+        for (char, &value) in self.src.bytes().zip(&self.bytes) {
+            write!(out, "{}", color_by_profile(char as char, value, max))?;
+        }
+        Ok(())
+    }
+
+    pub fn write_json(&self, mut out: impl Write) -> io::Result<()> {
+        writeln!(out, "{{")?;
+        writeln!(out, r#"  "total": {},"#, self.total)?;
+        writeln!(out, r#"  "bytes": {:?},"#, self.bytes)?;
+        write!(out, r#"  "loops": ["#)?;
+        for (i, lp) in self.loops.iter().enumerate() {
+            let (line, col) = line_col(self.src, lp.span.start());
+            let parent = lp
+                .parent
+                .map_or("null".to_owned(), |parent| parent.to_string());
+            let separator = if i == 0 { "" } else { "," };
+            let stats = match &lp.stats {
+                Some(stats) => {
+                    let histogram = stats
+                        .iterations
+                        .buckets()
+                        .map(|(range, count)| {
+                            format!("[{}, {}, {count}]", range.start(), range.end())
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    format!(
+                        ", \"balanced\": {}, \"histogram\": [{histogram}]",
+                        stats.balanced
+                    )
+                }
+                None => String::new(),
+            };
+            write!(
+                out,
+                "{separator}\n    {{ \"start\": {}, \"end\": {}, \
+                 \"line\": {line}, \"column\": {col}, \"parent\": {parent}, \
+                 \"entries\": {}, \"iterations\": {}, \
+                 \"inclusive\": {}, \"exclusive\": {}{stats} }}",
+                lp.span.start(),
+                lp.span.end(),
+                lp.entries,
+                lp.iterations,
+                lp.inclusive,
+                lp.exclusive
+            )?;
+        }
+        if !self.loops.is_empty() {
+            writeln!(out)?;
+            write!(out, "  ")?;
+        }
+        writeln!(out, "]")?;
+        writeln!(out, "}}")
+    }
+
+    pub fn write_callgrind(&self, file_name: &str, mut out: impl Write) -> io::Result<()> {
+        writeln!(out, "# callgrind format")?;
+        writeln!(out, "version: 1")?;
+        writeln!(out, "creator: brainfuck")?;
+        writeln!(out, "positions: line")?;
+        writeln!(out, "events: Statements")?;
+        writeln!(out, "summary: {}", self.total)?;
+        writeln!(out)?;
+        writeln!(out, "fl={file_name}")?;
+
+        writeln!(out, "fn=program")?;
+        writeln!(out, "1 {}", self.top_level)?;
+        self.write_callgrind_calls(None, &mut out)?;
+
+        for (i, lp) in self.loops.iter().enumerate() {
+            writeln!(out)?;
+            writeln!(out, "fn={}", frame_name(lp))?;
+            writeln!(out, "{} {}", self.line(lp), lp.exclusive)?;
+            self.write_callgrind_calls(Some(i), &mut out)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_callgrind_calls(&self, parent: Option<usize>, mut out: impl Write) -> io::Result<()> {
+        for lp in self.loops.iter().filter(|lp| lp.parent == parent) {
+            let line = self.line(lp);
+            writeln!(out, "cfn={}", frame_name(lp))?;
+            writeln!(out, "calls={} {line}", lp.entries)?;
+            writeln!(out, "{line} {}", lp.inclusive)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_folded(&self, mut out: impl Write) -> io::Result<()> {
+        if self.top_level > 0 {
+            writeln!(out, "program {}", self.top_level)?;
+        }
+
+        // the stacks of all loops, built on the stacks of their parents
+        let mut stacks = Vec::<String>::with_capacity(self.loops.len());
+        for lp in &self.loops {
+            let parent = lp.parent.map_or("program", |parent| &stacks[parent]);
+            let stack = format!("{parent};{}", frame_name(lp));
+            if lp.exclusive > 0 {
+                writeln!(out, "{stack} {}", lp.exclusive)?;
+            }
+            stacks.push(stack);
+        }
+
+        Ok(())
+    }
+
+    pub fn write_loops(&self, mut out: impl Write) -> io::Result<()> {
+        let mut loops = self.loops.iter().collect::<Vec<_>>();
+        loops.sort_by_key(|lp| std::cmp::Reverse(lp.inclusive));
+
+        writeln!(
+            out,
+            "{:<16} {:>12} {:>12} {:>14} {:>8}  iterations per entry",
+            "loop", "entries", "iterations", "statements", "balanced"
+        )?;
+        for lp in loops {
+            let (line, col) = line_col(self.src, lp.span.start());
+            let (balanced, histogram) = match &lp.stats {
+                Some(stats) if lp.iterations > 0 => (
+                    if stats.balanced { "yes" } else { "no" },
+                    stats
+                        .iterations
+                        .buckets()
+                        .map(|(range, count)| match range.start() == range.end() {
+                            true => format!("{}: {count}", range.start()),
+                            false => format!("{}-{}: {count}", range.start(), range.end()),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+                Some(_) => ("-", "never ran".to_owned()),
+                None => ("?", String::new()),
+            };
+            writeln!(
+                out,
+                "{:<16} {:>12} {:>12} {:>14} {balanced:>8}  {histogram}",
+                format!("{line}:{col}"),
+                lp.entries,
+                lp.iterations,
+                lp.inclusive
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn line(&self, lp: &LoopProfile) -> usize {
+        line_col(self.src, lp.span.start()).0
+    }
+}
+
+/// How often every statement was executed, which is how often its basic block was entered
+pub fn stmt_counts(lir: &Lir, block_counts: &[u64]) -> Vec<u64> {
+    assert_eq!(lir.stmts().len(), block_counts.len());
+
+    let mut count = 0;
+    let mut block_start = true;
+    let mut stmt_counts = Vec::with_capacity(block_counts.len());
+    for (stmt, &block_count) in lir.stmts().iter().zip(block_counts) {
+        if block_start {
+            count = block_count;
+        }
+        stmt_counts.push(count);
+        block_start = matches!(
+            stmt,
+            Stmt::JmpIfZero(_)
+                | Stmt::JmpIfNonZero(_)
+                | Stmt::DefineProc(_)
+                | Stmt::Call
+                | Stmt::Return
+        );
+    }
+    stmt_counts
+}
+
+fn frame_name(lp: &LoopProfile) -> String {
+    format!("loop@{}..{}", lp.span.start(), lp.span.end())
+}
+
+fn line_col(src: &str, offset: usize) -> (usize, usize) {
+    let before = &src.as_bytes()[..offset];
+    let line_start = before
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    let line = before.iter().filter(|&&b| b == b'\n').count();
+    (line + 1, offset - line_start + 1)
+}
+
+fn color_by_profile(char: char, value: u64, max: u64) -> impl Display {
+    let max = max as f64;
+    let value = value as f64;
+    let ratio = value / max;
+    let logged = -ratio.log10();
+    let logged = (logged * 100.) as u64;
+
+    match logged {
+        0..=15 => char.bright_red().to_string(),
+        16..=70 => char.yellow().to_string(),
+        71..=300 => char.green().to_string(),
+        _ => char.default_color().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+    use proptest::prelude::*;
+
+    use super::{LoopCollector, Profile, ProfileFormat};
+    use crate::{hir, lir, lir::interpreter::Interpreter, parse, CompileOptions, Program};
+
+    fn profile(src: &str, format: ProfileFormat) -> String {
+        let alloc = Bump::new();
+        let ast = parse::parse(&alloc, src.bytes().enumerate()).unwrap();
+        let hir = hir::optimized_hir(&alloc, &ast);
+        let lir = lir::generate(&hir);
+
+        let mut block_counts = vec![0; lir.stmts().len()];
+        lir::interpreter::run(&lir, std::io::sink(), [].as_slice(), |ip| {
+            block_counts[ip] += 1
+        });
+        let mut profile = Profile::new(src, &lir, &block_counts);
+
+        if let ProfileFormat::Loops = format {
+            let mut collector = LoopCollector::new(&lir);
+            lir::interpreter::run(&lir, std::io::sink(), [].as_slice(), &mut collector);
+            let (loop_block_counts, stats) = collector.finish();
+            assert_eq!(block_counts, loop_block_counts);
+            profile.set_loop_stats(stats);
+        }
+
+        let mut out = Vec::new();
+        profile.write(format, "test.bf", &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    const NESTED: &str = "++[>+++\n[>++<-]<-]>>.";
+
+    #[test]
+    fn json() {
+        insta::assert_snapshot!(profile(NESTED, ProfileFormat::Json));
+    }
+
+    #[test]
+    fn callgrind() {
+        insta::assert_snapshot!(profile(NESTED, ProfileFormat::Callgrind));
+    }
+
+    #[test]
+    fn folded() {
+        insta::assert_snapshot!(profile(NESTED, ProfileFormat::Folded));
+    }
+
+    #[test]
+    fn loops() {
+        // the second loop scans, the third one is never entered
+        let src = "+++[>++++[>+<-]<-]>>[>]>[+]";
+        insta::assert_snapshot!(profile(src, ProfileFormat::Loops));
+    }
+
+    #[test]
+    fn recursive_loops() {
+        // the loop runs once on every level, and calls the procedure again until the counter is 0
+        let options = CompileOptions {
+            dialect: "pbrain".parse().unwrap(),
+            ..CompileOptions::default()
+        };
+        let program = Program::compile("+(>[-<:>]<)>+++<:", &options).unwrap();
+
+        let mut collector = LoopCollector::new(program.lir());
+        lir::interpreter::run(
+            program.lir(),
+            std::io::sink(),
+            [].as_slice(),
+            &mut collector,
+        );
+        let (_, stats) = collector.finish();
+
+        let buckets: Vec<_> = stats[0].iterations.buckets().collect();
+        assert_eq!(buckets, [(0..=0, 1), (1..=1, 3)]);
+        assert!(stats[0].balanced);
+    }
+
+    proptest! {
+        #[test]
+        fn block_counts_reconstruct_stmt_counts(program in crate::difftest::arb_program()) {
+            let src = program.render();
+            let alloc = Bump::new();
+            let ast = parse::parse(&alloc, src.bytes().enumerate()).unwrap();
+            let hir = hir::optimized_hir(&alloc, &ast);
+            let lir = lir::generate(&hir);
+
+            let mut block_counts = vec![0; lir.stmts().len()];
+            let mut interpreter = Interpreter::new(
+                &lir,
+                std::io::sink(),
+                std::io::repeat(0),
+                |ip| block_counts[ip] += 1,
+                (),
+            );
+            let mut expected = vec![0; lir.stmts().len()];
+            loop {
+                let ip = interpreter.ip();
+                if !interpreter.step() {
+                    break;
+                }
+                expected[ip] += 1;
+            }
+            // `step` stops in front of the `End`
+            *expected.last_mut().unwrap() = 1;
+
+            prop_assert_eq!(super::stmt_counts(&lir, &block_counts), expected);
+        }
+    }
+}
@@ -0,0 +1,455 @@
+//! the library API for compiling a program once and running it as often as needed
+//!
+//! ```
+//! use brainfuck::{CompileOptions, Program, RunOptions};
+//!
+//! let program = Program::compile("++++++++[>++++++++<-]>+.", &CompileOptions::default()).unwrap();
+//!
+//! let mut output = Vec::new();
+//! program.run(&RunOptions::default(), std::io::empty(), &mut output);
+//! assert_eq!(output, b"A");
+//! ```
+
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    io::{self, Read, Write},
+};
+
+use bumpalo::Bump;
+
+use crate::{
+    hir, isomorph,
+    isomorph::Language,
+    lir::{
+        self,
+        bytecode::{self, BytecodeError},
+        interpreter::FinalState,
+        Lir,
+    },
+    parse::{Dialect, ParseError},
+    watch::Watchpoint,
+    Input,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// The extensions to the classic commands
+    pub dialect: Dialect,
+    /// The language the source is written in
+    pub language: Language,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Cells to log to stderr when they are written to, by the optimized code (see
+    /// [`crate::watch`])
+    pub watch: Vec<Watchpoint>,
+    /// The input of the program, instead of the reader passed to [`Program::run`]
+    pub input: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    /// The brackets don't match up, or are nested too deep
+    Parse,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::Parse => f.write_str("Failed to parse brainfuck code"),
+        }
+    }
+}
+
+impl Error for CompileError {}
+
+impl From<ParseError> for CompileError {
+    fn from(_: ParseError) -> Self {
+        CompileError::Parse
+    }
+}
+
+/// A compiled and optimized program, which doesn't borrow from anything
+#[derive(Debug, Clone)]
+pub struct Program {
+    lir: Lir,
+    /// the input after the `!`, with the `input` dialect
+    inline_input: Option<Vec<u8>>,
+}
+
+impl Program {
+    pub fn compile(src: &str, options: &CompileOptions) -> Result<Self, CompileError> {
+        let (src, inline_input) = match options.dialect.inline_input {
+            true => options.language.split_inline_input(src),
+            false => (src, None),
+        };
+
+        let ast_alloc = Bump::new();
+        let parsed = isomorph::parse(&ast_alloc, src, &options.language, options.dialect)?;
+
+        let hir_alloc = Bump::new();
+        let optimized_hir = hir::optimized_hir(&hir_alloc, &parsed);
+
+        Ok(Self {
+            lir: lir::generate(&optimized_hir),
+            inline_input: inline_input.map(|input| input.as_bytes().to_vec()),
+        })
+    }
+
+    /// Runs the program to its end. It reads the input from `options` if there is any, otherwise
+    /// the inline input of the program, and `input` only if there is neither. Reading after the
+    /// end of the input leaves the cell unchanged, even if the input that ended isn't `input`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if reading the input or writing the output fails.
+    pub fn run<R, W>(&self, options: &RunOptions, input: R, output: W) -> FinalState
+    where
+        R: Read,
+        W: Write,
+    {
+        let input = match (&options.input, &self.inline_input) {
+            (Some(fixed), _) | (None, Some(fixed)) => Input::Inline(fixed),
+            (None, None) => Input::Stdin(input),
+        };
+        crate::execute(&self.lir, output, input, |_| {}, &options.watch)
+    }
+
+    /// Loads a program that was written with [`Program::write_bytecode`]. Fails if the code
+    /// could break the interpreter.
+    pub fn from_bytecode<R: Read>(input: R) -> Result<Self, BytecodeError> {
+        let (lir, inline_input) = bytecode::read(input)?;
+        Ok(Self { lir, inline_input })
+    }
+
+    /// Writes the compiled program to `out`, see [`lir::bytecode`]
+    pub fn write_bytecode<W: Write>(&self, out: W) -> io::Result<()> {
+        bytecode::write(&self.lir, self.inline_input(), out)
+    }
+
+    /// The compiled code
+    pub fn lir(&self) -> &Lir {
+        &self.lir
+    }
+
+    /// The input after the `!` in the source, with the `input` dialect
+    pub fn inline_input(&self) -> Option<&[u8]> {
+        self.inline_input.as_deref()
+    }
+
+    /// Like [`Program::run`], but waits for input and output without blocking the thread, so many
+    /// programs can run on the same runtime. The program runs in slices of a few thousand
+    /// statements, after which the output is written and the task yields to the runtime.
+    ///
+    /// Returns an [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) error if the program
+    /// reads more input than there is, without falling back to `input` when the input from
+    /// `options` or the inline input has ended.
+    #[cfg(feature = "async")]
+    pub async fn run_async<R, W>(
+        &self,
+        options: &RunOptions,
+        input: R,
+        output: W,
+    ) -> std::io::Result<()>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        use crate::{vm::Vm, watch::WatchLogger};
+
+        // like `run`, the input from the options replaces the inline input, and either of them
+        // replaces `input`
+        let fixed_input = options.input.as_deref().or(self.inline_input());
+
+        // only pay for the watchpoints if there are any
+        if options.watch.is_empty() {
+            drive(Vm::new(self), fixed_input, input, output).await
+        } else {
+            let logger = WatchLogger::new(&options.watch, &self.lir, std::io::stderr());
+            drive(Vm::with_watcher(self, logger), fixed_input, input, output).await
+        }
+    }
+}
+
+/// Runs `vm` to the end for [`Program::run_async`]. With `fixed_input`, the program only gets
+/// that input, instead of `input`.
+#[cfg(feature = "async")]
+async fn drive<H, R, W>(
+    mut vm: crate::vm::Vm<'_, H>,
+    fixed_input: Option<&[u8]>,
+    mut input: R,
+    mut output: W,
+) -> std::io::Result<()>
+where
+    H: crate::lir::interpreter::Watcher,
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::vm::{Stop, Until};
+
+    /// also bounds how much output is buffered
+    const SLICE_STEPS: u64 = 4096;
+
+    if let Some(fixed) = fixed_input {
+        vm.replace_input(fixed);
+    }
+    let mut buf = [0; 1024];
+
+    loop {
+        let stop = vm.run_until(Until::Steps(SLICE_STEPS));
+
+        let out = vm.take_output();
+        if !out.is_empty() {
+            output.write_all(&out).await?;
+        }
+
+        match stop {
+            Stop::InputNeeded if fixed_input.is_some() => {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            Stop::InputNeeded => {
+                // the program might have asked a question that needs to be seen first
+                output.flush().await?;
+                match input.read(&mut buf).await? {
+                    0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                    n => vm.feed_input(&buf[..n]),
+                }
+            }
+            Stop::End => return output.flush().await,
+            _ => tokio::task::yield_now().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompileError, CompileOptions, Program, RunOptions};
+
+    #[test]
+    fn run_twice() {
+        let program = Program::compile(",[.,]", &CompileOptions::default()).unwrap();
+
+        for input in [b"abc\0".as_slice(), b"hello\0"] {
+            let mut output = Vec::new();
+            program.run(&RunOptions::default(), input, &mut output);
+            assert_eq!(output, &input[..input.len() - 1]);
+        }
+    }
+
+    #[test]
+    fn options() {
+        let options = CompileOptions {
+            dialect: "input".parse().unwrap(),
+            language: "ook".parse().unwrap(),
+        };
+        // `!` is part of the tokens, not the start of the input
+        let program = Program::compile("Ook. Ook! Ook! Ook.", &options).unwrap();
+
+        let mut output = Vec::new();
+        program.run(&RunOptions::default(), b"x".as_slice(), &mut output);
+        assert_eq!(output, b"x");
+    }
+
+    #[test]
+    fn input_option() {
+        let program = Program::compile(",[.,]", &CompileOptions::default()).unwrap();
+        let options = RunOptions {
+            input: Some(b"option\0".to_vec()),
+            ..RunOptions::default()
+        };
+
+        let mut output = Vec::new();
+        program.run(&options, b"reader\0".as_slice(), &mut output);
+        assert_eq!(output, b"option");
+
+        // and it replaces the inline input
+        let compile_options = CompileOptions {
+            dialect: "input".parse().unwrap(),
+            ..CompileOptions::default()
+        };
+        let program = Program::compile(",[.,]!inline\0", &compile_options).unwrap();
+        let mut output = Vec::new();
+        program.run(&options, [].as_slice(), &mut output);
+        assert_eq!(output, b"option");
+    }
+
+    #[test]
+    fn inline_input_ends() {
+        let options = CompileOptions {
+            dialect: "input".parse().unwrap(),
+            ..CompileOptions::default()
+        };
+        let program = Program::compile(",.,.!a", &options).unwrap();
+
+        // the reader is never read, the second `,` keeps the `a`
+        let mut output = Vec::new();
+        program.run(&RunOptions::default(), b"b".as_slice(), &mut output);
+        assert_eq!(output, b"aa");
+    }
+
+    #[test]
+    fn bytecode() {
+        let options = CompileOptions {
+            dialect: "input".parse().unwrap(),
+            ..CompileOptions::default()
+        };
+        let program = Program::compile(",[.,]!hi\0", &options).unwrap();
+        let mut bytecode = Vec::new();
+        program.write_bytecode(&mut bytecode).unwrap();
+
+        let loaded = Program::from_bytecode(bytecode.as_slice()).unwrap();
+        let mut output = Vec::new();
+        loaded.run(&RunOptions::default(), [].as_slice(), &mut output);
+        assert_eq!(output, b"hi");
+    }
+
+    #[test]
+    fn invalid() {
+        let result = Program::compile("[", &CompileOptions::default());
+        assert_eq!(result.unwrap_err(), CompileError::Parse);
+    }
+
+    #[cfg(feature = "async")]
+    mod run_async {
+        use tokio::io::AsyncWriteExt;
+
+        use super::{CompileOptions, Program, RunOptions};
+
+        fn block_on<F: std::future::Future>(future: F) -> F::Output {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(future)
+        }
+
+        #[test]
+        fn interactive() {
+            // asks for every byte with a `?` and echoes it, until a zero
+            let program = Program::compile(
+                "+++++++[>+++++++++<-]>.<,[.>.<,]",
+                &CompileOptions::default(),
+            )
+            .unwrap();
+            let (mut client, server) = tokio::io::duplex(64);
+            let (server_in, server_out) = tokio::io::split(server);
+
+            let options = RunOptions::default();
+            let output = block_on(async {
+                let run = program.run_async(&options, server_in, server_out);
+                let feed = async {
+                    for byte in b"ab\0" {
+                        tokio::task::yield_now().await;
+                        client.write_all(&[*byte]).await.unwrap();
+                    }
+                    let mut output = Vec::new();
+                    tokio::io::AsyncReadExt::read_to_end(&mut client, &mut output)
+                        .await
+                        .unwrap();
+                    output
+                };
+                let (result, output) = tokio::join!(run, feed);
+                result.unwrap();
+                output
+            });
+
+            assert_eq!(output, b"?a?b?");
+        }
+
+        #[test]
+        fn many_programs() {
+            let program = Program::compile(
+                include_str!("../benches/fizzbuzz.bf"),
+                &CompileOptions::default(),
+            )
+            .unwrap();
+            let mut expected = Vec::new();
+            program.run(&Default::default(), [].as_slice(), &mut expected);
+
+            let run = || async {
+                let mut output = Vec::new();
+                program
+                    .run_async(&RunOptions::default(), [].as_slice(), &mut output)
+                    .await
+                    .unwrap();
+                output
+            };
+            let outputs = block_on(async { tokio::join!(run(), run(), run()) });
+
+            for output in [outputs.0, outputs.1, outputs.2] {
+                assert_eq!(output, expected);
+            }
+        }
+
+        #[test]
+        fn missing_input() {
+            let program = Program::compile(",,", &CompileOptions::default()).unwrap();
+            let result = block_on(program.run_async(
+                &RunOptions::default(),
+                b"x".as_slice(),
+                tokio::io::sink(),
+            ));
+            assert_eq!(
+                result.unwrap_err().kind(),
+                std::io::ErrorKind::UnexpectedEof
+            );
+        }
+
+        #[test]
+        fn inline_input_ends() {
+            let options = CompileOptions {
+                dialect: "input".parse().unwrap(),
+                ..CompileOptions::default()
+            };
+            let program = Program::compile(",.,.!a", &options).unwrap();
+
+            let mut output = Vec::new();
+            let result =
+                block_on(program.run_async(&RunOptions::default(), b"b".as_slice(), &mut output));
+            assert_eq!(
+                result.unwrap_err().kind(),
+                std::io::ErrorKind::UnexpectedEof
+            );
+            assert_eq!(output, b"a");
+        }
+
+        #[test]
+        fn options() {
+            let program = Program::compile(",[.,]", &CompileOptions::default()).unwrap();
+            let options = RunOptions {
+                watch: vec!["0=0".parse().unwrap()],
+                input: Some(b"hi\0".to_vec()),
+            };
+
+            // the input from the options replaces the reader
+            let mut output = Vec::new();
+            let result = block_on(program.run_async(&options, b"no".as_slice(), &mut output));
+            result.unwrap();
+            assert_eq!(output, b"hi");
+
+            // and the inline input
+            let inline = CompileOptions {
+                dialect: "input".parse().unwrap(),
+                ..CompileOptions::default()
+            };
+            let program = Program::compile(",[.,]!inline\0", &inline).unwrap();
+            let mut output = Vec::new();
+            let result = block_on(program.run_async(&options, [].as_slice(), &mut output));
+            result.unwrap();
+            assert_eq!(output, b"hi");
+
+            let options = RunOptions {
+                input: Some(b"hi".to_vec()),
+                ..RunOptions::default()
+            };
+            let result = block_on(program.run_async(&options, b"\0".as_slice(), tokio::io::sink()));
+            assert_eq!(
+                result.unwrap_err().kind(),
+                std::io::ErrorKind::UnexpectedEof
+            );
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! generates short brainfuck programs that print a given text
+//!
+//! The program first sets up a few cells near the values of the text with a multiplication loop,
+//! like `++++++++[>+++++++++>++++++++++++<<-]`, and then prints every byte from the cell that is
+//! cheapest to reach and adjust. Many combinations of cells and loop counters are tried and the
+//! shortest program wins.
+
+/// the most cells that are set up by the multiplication loop
+const MAX_CELLS: usize = 6;
+/// the largest loop counter of the multiplication loop
+const MAX_COUNTER: u8 = 20;
+
+/// Generates a brainfuck program that prints `text`
+pub fn generate(text: &[u8]) -> String {
+    // without a setup loop, every byte is printed from cell 0
+    let mut best = print_from(String::new(), vec![0], text);
+
+    for cells in 1..=MAX_CELLS.min(text.len()) {
+        let centers = cluster(text, cells);
+        for counter in 2..=MAX_COUNTER {
+            let mut factors = centers
+                .iter()
+                .map(|&center| ((center + u32::from(counter) / 2) / u32::from(counter)) as u8)
+                .collect::<Vec<_>>();
+            let mut program = with_setup(counter, &factors, text);
+
+            // the rounded centers are only a guess, nudge the factors while that helps
+            let mut improved = true;
+            while improved {
+                improved = false;
+                for i in 0..factors.len() {
+                    for nudged in [factors[i].wrapping_sub(1), factors[i].wrapping_add(1)] {
+                        let old = std::mem::replace(&mut factors[i], nudged);
+                        let candidate = with_setup(counter, &factors, text);
+                        if candidate.len() < program.len() {
+                            program = candidate;
+                            improved = true;
+                        } else {
+                            factors[i] = old;
+                        }
+                    }
+                }
+            }
+
+            if program.len() < best.len() {
+                best = program;
+            }
+        }
+    }
+
+    best
+}
+
+/// The program that prints `text` after setting cell `i + 1` to `counter * factors[i]`
+fn with_setup(counter: u8, factors: &[u8], text: &[u8]) -> String {
+    let mut setup = "+".repeat(counter.into());
+    setup.push('[');
+    for &factor in factors {
+        setup.push('>');
+        setup.push_str(&"+".repeat(factor.into()));
+    }
+    setup.push_str(&"<".repeat(factors.len()));
+    setup.push_str("-]");
+
+    let values = std::iter::once(0)
+        .chain(factors.iter().map(|factor| factor.wrapping_mul(counter)))
+        .collect();
+    print_from(setup, values, text)
+}
+
+/// Appends the code to print `text` to `program`, starting at cell 0 with the cells set to
+/// `values`. Every byte is printed from the cell that takes the fewest commands to reach and
+/// adjust, so cells near previously printed bytes get reused.
+fn print_from(mut program: String, mut values: Vec<u8>, text: &[u8]) -> String {
+    let mut ptr: usize = 0;
+
+    for &byte in text {
+        let (cell, _) = values
+            .iter()
+            .enumerate()
+            .map(|(cell, &value)| (cell, ptr.abs_diff(cell) + distance(value, byte)))
+            .min_by_key(|&(_, cost)| cost)
+            .unwrap();
+
+        let moves = match cell > ptr {
+            true => ">".repeat(cell - ptr),
+            false => "<".repeat(ptr - cell),
+        };
+        program.push_str(&moves);
+        ptr = cell;
+
+        let up = byte.wrapping_sub(values[cell]);
+        let adjust = match up <= 128 {
+            true => "+".repeat(up.into()),
+            false => "-".repeat(0u8.wrapping_sub(up).into()),
+        };
+        program.push_str(&adjust);
+        values[cell] = byte;
+
+        program.push('.');
+    }
+
+    program
+}
+
+/// The number of `+` or `-` needed to change `from` to `to`
+fn distance(from: u8, to: u8) -> usize {
+    let up = to.wrapping_sub(from);
+    usize::from(up.min(0u8.wrapping_sub(up)))
+}
+
+/// Groups the bytes of `text` into `count` clusters of nearby values, returning their centers in
+/// the order the clusters are first used in the text
+fn cluster(text: &[u8], count: usize) -> Vec<u32> {
+    let mut sorted = text.to_vec();
+    sorted.sort_unstable();
+
+    // start with evenly spaced quantiles, then refine with a few rounds of k-means
+    let mut centers = (0..count)
+        .map(|i| u32::from(sorted[(2 * i + 1) * sorted.len() / (2 * count)]))
+        .collect::<Vec<_>>();
+
+    let nearest = |centers: &[u32], byte: u8| {
+        (0..centers.len())
+            .min_by_key(|&i| centers[i].abs_diff(u32::from(byte)))
+            .unwrap()
+    };
+
+    for _ in 0..10 {
+        let mut sums = vec![(0, 0); count];
+        for &byte in &sorted {
+            let (sum, len) = &mut sums[nearest(&centers, byte)];
+            *sum += u32::from(byte);
+            *len += 1;
+        }
+        for (center, &(sum, len)) in centers.iter_mut().zip(&sums) {
+            if let Some(mean) = (sum + len / 2).checked_div(len) {
+                *center = mean;
+            }
+        }
+    }
+
+    let mut first_use = vec![usize::MAX; count];
+    for (idx, &byte) in text.iter().enumerate() {
+        let cluster = nearest(&centers, byte);
+        first_use[cluster] = first_use[cluster].min(idx);
+    }
+    let mut order = (0..count)
+        .filter(|&i| first_use[i] != usize::MAX)
+        .collect::<Vec<_>>();
+    order.sort_by_key(|&i| first_use[i]);
+    order.into_iter().map(|i| centers[i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+    use proptest::{collection::vec, prelude::*};
+
+    use crate::{hir, lir, parse};
+
+    fn run(program: &str) -> Vec<u8> {
+        let alloc = Bump::new();
+        let ast = parse::parse(&alloc, program.bytes().enumerate()).unwrap();
+        let hir = hir::optimized_hir(&alloc, &ast);
+        let lir = lir::generate(&hir);
+        let mut stdout = Vec::new();
+        lir::interpreter::run(&lir, &mut stdout, [].as_slice(), |_| {});
+        stdout
+    }
+
+    #[test]
+    fn hello_world() {
+        let program = super::generate(b"Hello, World!\n");
+        assert_eq!(run(&program), b"Hello, World!\n");
+        insta::assert_snapshot!(program);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(super::generate(b""), "");
+    }
+
+    proptest! {
+        #[test]
+        // the interpreter writes bytes above 127 as UTF-8 chars
+        fn generated_program_prints_text(text in vec(0..128u8, 0..64)) {
+            let program = super::generate(&text);
+            prop_assert_eq!(run(&program), text);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! recording executions and replaying them
+//!
+//! A trace contains the source code and a record for every executed LIR statement, with the
+//! position before it and the cells it wrote. Every input byte is recorded right before the
+//! statement that read it, so a replay needs nothing but the trace.
+//!
+//! The format is a stream of little records, numbers are LEB128 varints:
+//!
+//! ```text
+//! b"BFTRACE" version:u8 source_len:varint source:[u8]
+//! 0 ip:varint ptr:varint write_count:u8 (cell:varint value:u8)*   a statement
+//! 1 byte:u8                                                       an input byte
+//! 2 steps:varint                                                  the end of the program
+//! ```
+//!
+//! The replay compiles the source again and checks every statement against the trace, so it
+//! notices if the compiler or interpreter behave differently than the recording one did.
+
+use std::{
+    fmt::{Display, Formatter},
+    io::{self, BufWriter, Read, Write},
+};
+
+use bumpalo::Bump;
+
+use crate::{
+    encoding::{self, write_varint},
+    hir,
+    lir::{
+        self,
+        interpreter::{CellWrite, Interpreter, WriteRecorder},
+        Stmt,
+    },
+    parse::{self, ParseError},
+};
+
+const MAGIC: &[u8] = b"BFTRACE";
+const VERSION: u8 = 1;
+
+const STEP: u8 = 0;
+const INPUT: u8 = 1;
+const END: u8 = 2;
+
+#[derive(Debug)]
+pub enum TraceError {
+    Parse(ParseError),
+    Io(io::Error),
+    /// the file is not a trace, or a trace of an unsupported version
+    Invalid(&'static str),
+    /// the replay did something else than the recording at this step
+    Diverged {
+        step: u64,
+    },
+}
+
+impl Display for TraceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceError::Parse(_) => write!(f, "Failed to parse brainfuck code"),
+            TraceError::Io(err) => write!(f, "Failed to read or write trace: {err}"),
+            TraceError::Invalid(reason) => write!(f, "Invalid trace: {reason}"),
+            TraceError::Diverged { step } => {
+                write!(f, "Replay diverged from the trace at step {step}")
+            }
+        }
+    }
+}
+
+impl From<ParseError> for TraceError {
+    fn from(err: ParseError) -> Self {
+        Self::Parse(err)
+    }
+}
+
+impl From<io::Error> for TraceError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Runs `src` like [`crate::run`] and records the execution to `trace`
+pub fn record<W, R, T>(src: &str, stdout: W, stdin: R, trace: T) -> Result<(), TraceError>
+where
+    W: Write,
+    R: Read,
+    T: Write,
+{
+    let lir = compile(src)?;
+
+    let mut trace = BufWriter::new(trace);
+    trace.write_all(MAGIC)?;
+    trace.write_all(&[VERSION])?;
+    write_varint(&mut trace, src.len() as u64)?;
+    trace.write_all(src.as_bytes())?;
+
+    let mut interpreter = Interpreter::new(&lir, stdout, stdin, |_| {}, WriteRecorder::default());
+    let mut steps = 0;
+    loop {
+        let ip = interpreter.ip();
+        let ptr = interpreter.ptr();
+        if !interpreter.step() {
+            break;
+        }
+        steps += 1;
+
+        let writes = std::mem::take(interpreter.watcher_mut()).writes;
+        if let Stmt::In = lir.stmts()[ip] {
+            let read = writes[0].expect("`In` always writes the cell");
+            trace.write_all(&[INPUT, read.new])?;
+        }
+
+        trace.write_all(&[STEP])?;
+        write_varint(&mut trace, ip as u64)?;
+        write_varint(&mut trace, ptr as u64)?;
+        let writes = writes.iter().flatten().collect::<Vec<_>>();
+        trace.write_all(&[writes.len() as u8])?;
+        for write in writes {
+            write_varint(&mut trace, write.idx as u64)?;
+            trace.write_all(&[write.new])?;
+        }
+    }
+
+    trace.write_all(&[END])?;
+    write_varint(&mut trace, steps)?;
+    trace.flush()?;
+    Ok(())
+}
+
+/// Runs the program recorded in `trace` again, with the input from the trace
+pub fn replay<W, T>(mut trace: T, stdout: W) -> Result<(), TraceError>
+where
+    W: Write,
+    T: Read,
+{
+    let mut magic = [0; MAGIC.len()];
+    trace
+        .read_exact(&mut magic)
+        .map_err(|_| TraceError::Invalid("not a trace"))?;
+    if magic != MAGIC {
+        return Err(TraceError::Invalid("not a trace"));
+    }
+    if read_u8(&mut trace)? != VERSION {
+        return Err(TraceError::Invalid("unsupported version"));
+    }
+    let src = encoding::read_bytes(&mut trace, usize::MAX).map_err(invalid)?;
+    let src = String::from_utf8(src).map_err(|_| TraceError::Invalid("source is not UTF-8"))?;
+
+    let lir = compile(&src)?;
+
+    let mut interpreter = Interpreter::new(
+        &lir,
+        stdout,
+        PendingInput(None),
+        |_| {},
+        WriteRecorder::default(),
+    );
+    let mut step = 0;
+    loop {
+        let diverged = TraceError::Diverged { step };
+        match read_u8(&mut trace)? {
+            INPUT => interpreter.stdin_mut().0 = Some(read_u8(&mut trace)?),
+            STEP => {
+                let ip = read_varint(&mut trace)? as usize;
+                let ptr = read_varint(&mut trace)? as usize;
+                let mut writes = Vec::new();
+                for _ in 0..read_u8(&mut trace)? {
+                    writes.push((read_varint(&mut trace)? as usize, read_u8(&mut trace)?));
+                }
+
+                let stmt = lir.stmts()[interpreter.ip()];
+                let missing_input = matches!(stmt, Stmt::In) && interpreter.stdin_mut().0.is_none();
+                if (ip, ptr) != (interpreter.ip(), interpreter.ptr()) || missing_input {
+                    return Err(diverged);
+                }
+                if !interpreter.step() {
+                    return Err(diverged);
+                }
+
+                let actual = std::mem::take(interpreter.watcher_mut()).writes;
+                let actual = actual
+                    .iter()
+                    .flatten()
+                    .map(|&CellWrite { idx, new, .. }| (idx, new));
+                if !actual.eq(writes) {
+                    return Err(diverged);
+                }
+                step += 1;
+            }
+            END => {
+                let steps = read_varint(&mut trace)?;
+                if steps != step || interpreter.step() {
+                    return Err(diverged);
+                }
+                return Ok(());
+            }
+            _ => return Err(TraceError::Invalid("unknown record")),
+        }
+    }
+}
+
+fn compile(src: &str) -> Result<lir::Lir, ParseError> {
+    let ast_alloc = Bump::new();
+    let parsed = parse::parse(&ast_alloc, src.bytes().enumerate())?;
+
+    let hir_alloc = Bump::new();
+    let optimized_hir = hir::optimized_hir(&hir_alloc, &parsed);
+
+    Ok(lir::generate(&optimized_hir))
+}
+
+/// The input byte from the last input record, read by the next `In`
+struct PendingInput(Option<u8>);
+
+impl Read for PendingInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match (self.0.take(), buf.first_mut()) {
+            (Some(byte), Some(first)) => {
+                *first = byte;
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+fn read_varint(input: &mut impl Read) -> Result<u64, TraceError> {
+    encoding::read_varint(input).map_err(invalid)
+}
+
+fn read_u8(input: &mut impl Read) -> Result<u8, TraceError> {
+    encoding::read_u8(input).map_err(invalid)
+}
+
+fn invalid(err: io::Error) -> TraceError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => TraceError::Invalid("unexpected end of trace"),
+        _ => TraceError::Invalid("varint is too long"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TraceError;
+
+    const ECHO_TWICE: &str = ",[..,]";
+
+    fn record(src: &str, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut stdout = Vec::new();
+        let mut trace = Vec::new();
+        super::record(src, &mut stdout, input, &mut trace).unwrap();
+        (stdout, trace)
+    }
+
+    #[test]
+    fn replay_without_stdin() {
+        let (stdout, trace) = record(ECHO_TWICE, b"hi\0");
+        assert_eq!(stdout, b"hhii");
+
+        let mut replayed = Vec::new();
+        super::replay(trace.as_slice(), &mut replayed).unwrap();
+        assert_eq!(replayed, stdout);
+    }
+
+    #[test]
+    fn replay_notices_divergence() {
+        let (_, mut trace) = record(ECHO_TWICE, b"hi\0");
+        // change how the second input byte was recorded
+        let second_input = trace
+            .iter()
+            .rposition(|&byte| byte == b'i')
+            .expect("input is recorded");
+        trace[second_input] = b'j';
+
+        let mut replayed = Vec::new();
+        let result = super::replay(trace.as_slice(), &mut replayed);
+        assert!(
+            matches!(result, Err(TraceError::Diverged { .. })),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn invalid_trace() {
+        let result = super::replay(b"+[-]".as_slice(), Vec::new());
+        assert!(matches!(result, Err(TraceError::Invalid(_))), "{result:?}");
+
+        // claims a huge source, which must not be allocated up front
+        let mut huge_source = super::MAGIC.to_vec();
+        huge_source.push(super::VERSION);
+        huge_source.extend([0xff; 9]);
+        huge_source.push(0x01);
+        huge_source.extend(b"+[-]");
+        let result = super::replay(huge_source.as_slice(), Vec::new());
+        assert!(matches!(result, Err(TraceError::Invalid(_))), "{result:?}");
+    }
+}
@@ -0,0 +1,264 @@
+//! an interpreter session that is driven by the host, one piece at a time
+//!
+//! Unlike [`Program::run`], the [`Vm`] never blocks. Input is fed to it whenever the host has
+//! some, output is collected until the host takes it, and the program is paused whenever it needs
+//! input that isn't there yet.
+//!
+//! ```
+//! use brainfuck::{vm::{Stop, Until}, CompileOptions, Program, Vm};
+//!
+//! let program = Program::compile(",[.,]", &CompileOptions::default()).unwrap();
+//! let mut vm = Vm::new(&program);
+//!
+//! assert_eq!(vm.run_until(Until::InputNeeded), Stop::InputNeeded);
+//! vm.feed_input(b"hi\0");
+//! assert_eq!(vm.run_until(Until::InputNeeded), Stop::End);
+//! assert_eq!(vm.take_output(), b"hi");
+//! ```
+
+mod snapshot;
+
+use std::collections::{HashSet, VecDeque};
+
+pub use snapshot::SnapshotError;
+
+use crate::{
+    lir::{
+        interpreter::{Interpreter, Watcher},
+        Stmt,
+    },
+    Program,
+};
+
+/// What [`Vm::run_until`] runs until. It always stops at the end of the program, and when the
+/// program needs input that hasn't been fed yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+    /// the next output
+    Output,
+    /// the program can't continue without more input
+    InputNeeded,
+    /// this many more statements have been executed
+    Steps(u64),
+    /// the next statement with a breakpoint, see [`Vm::add_breakpoint`]
+    Breakpoint,
+}
+
+/// Why the [`Vm`] stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// the last statement wrote output, which can be taken with [`Vm::take_output`]
+    Output,
+    /// the next statement reads input, but there is none, see [`Vm::feed_input`]
+    InputNeeded,
+    /// the number of steps passed to [`Until::Steps`] were executed
+    Steps,
+    /// the next statement has a breakpoint
+    Breakpoint,
+    /// the program has ended
+    End,
+}
+
+/// The output is collected, and the input is fed to it. It never profiles.
+type VmInterpreter<'p, H> = Interpreter<'p, Vec<u8>, VecDeque<u8>, fn(usize), H>;
+
+/// A session of a program, which notifies `H` about every write to the tape, see
+/// [`Vm::with_watcher`]
+pub struct Vm<'p, H = ()> {
+    interpreter: VmInterpreter<'p, H>,
+    breakpoints: HashSet<usize>,
+    steps: u64,
+}
+
+impl<'p> Vm<'p> {
+    /// A session at the start of `program`, with its inline input already fed
+    pub fn new(program: &'p Program) -> Self {
+        Self::with_watcher(program, ())
+    }
+}
+
+impl<'p, H: Watcher> Vm<'p, H> {
+    /// Like [`Vm::new`], but `watcher` is notified about every write to the tape
+    pub fn with_watcher(program: &'p Program, watcher: H) -> Self {
+        let input = program.inline_input().unwrap_or_default();
+        let interpreter = Interpreter::new(
+            program.lir(),
+            Vec::new(),
+            input.iter().copied().collect(),
+            (|_| {}) as fn(usize),
+            watcher,
+        );
+
+        Self {
+            interpreter,
+            breakpoints: HashSet::new(),
+            steps: 0,
+        }
+    }
+
+    /// Executes a single statement, unless the program needs input or has ended. Returns why it
+    /// should stop, if there is a reason.
+    pub fn step(&mut self) -> Option<Stop> {
+        let stmt = self.interpreter.code().stmts()[self.interpreter.ip()];
+        match stmt {
+            Stmt::End => return Some(Stop::End),
+            Stmt::In if self.interpreter.stdin_mut().is_empty() => return Some(Stop::InputNeeded),
+            _ => {}
+        }
+
+        self.interpreter.step();
+        self.steps += 1;
+
+        match stmt {
+            Stmt::Out => Some(Stop::Output),
+            _ => None,
+        }
+    }
+
+    /// Executes statements until `until` happens, the program needs more input or it ends
+    pub fn run_until(&mut self, until: Until) -> Stop {
+        let mut steps = 0;
+        loop {
+            if let Until::Steps(limit) = until {
+                if steps == limit {
+                    return Stop::Steps;
+                }
+            }
+            // don't stop at the breakpoint that we're continuing from
+            if until == Until::Breakpoint
+                && steps > 0
+                && self.breakpoints.contains(&self.interpreter.ip())
+            {
+                return Stop::Breakpoint;
+            }
+
+            match self.step() {
+                Some(Stop::Output) if until == Until::Output => return Stop::Output,
+                Some(Stop::Output) | None => {}
+                Some(stop) => return stop,
+            }
+            steps += 1;
+        }
+    }
+
+    /// Makes `input` available to the program, after any input that hasn't been read yet
+    pub fn feed_input(&mut self, input: &[u8]) {
+        self.interpreter.stdin_mut().extend(input);
+    }
+
+    /// Replaces all input that hasn't been read yet, including the inline input, with `input`
+    #[cfg(feature = "async")]
+    pub(crate) fn replace_input(&mut self, input: &[u8]) {
+        *self.interpreter.stdin_mut() = input.iter().copied().collect();
+    }
+
+    /// The output that was written since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.interpreter.stdout_mut())
+    }
+
+    /// Makes [`Until::Breakpoint`] stop in front of the statement at `ip`. The statements for a
+    /// position in the source can be found with [`Lir::location`](crate::lir::Lir::location).
+    pub fn add_breakpoint(&mut self, ip: usize) {
+        self.breakpoints.insert(ip);
+    }
+
+    pub fn remove_breakpoint(&mut self, ip: usize) {
+        self.breakpoints.remove(&ip);
+    }
+
+    /// The index of the next statement
+    pub fn ip(&self) -> usize {
+        self.interpreter.ip()
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.interpreter.ptr()
+    }
+
+    /// The value of the cell at `idx`
+    pub fn cell(&self, idx: usize) -> u8 {
+        self.interpreter.cell(idx)
+    }
+
+    /// The number of statements executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Stop, Until, Vm};
+    use crate::{CompileOptions, Program};
+
+    fn compile(src: &str) -> Program {
+        Program::compile(src, &CompileOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn output_and_input() {
+        // echoes every byte incremented by one, until a zero
+        let program = compile(",[+.,]");
+        let mut vm = Vm::new(&program);
+
+        assert_eq!(vm.run_until(Until::Output), Stop::InputNeeded);
+        vm.feed_input(b"ab");
+        assert_eq!(vm.run_until(Until::Output), Stop::Output);
+        assert_eq!(vm.take_output(), b"b");
+        assert_eq!(vm.run_until(Until::Output), Stop::Output);
+        assert_eq!(vm.run_until(Until::Output), Stop::InputNeeded);
+        assert_eq!(vm.take_output(), b"c");
+
+        vm.feed_input(&[0]);
+        assert_eq!(vm.run_until(Until::Output), Stop::End);
+        assert_eq!(vm.run_until(Until::Output), Stop::End);
+    }
+
+    #[test]
+    fn steps() {
+        let program = compile("++[>+.<-]>.");
+        let mut vm = Vm::new(&program);
+
+        assert_eq!(vm.step(), None);
+        assert_eq!(vm.run_until(Until::Steps(2)), Stop::Steps);
+        assert_eq!(vm.steps(), 3);
+        assert_eq!((vm.ip(), vm.ptr(), vm.cell(0)), (3, 1, 2));
+        assert_eq!(vm.run_until(Until::Steps(100)), Stop::End);
+        assert_eq!(vm.take_output(), [1, 2, 2]);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let program = compile("++[>+.<-]>.");
+        let lir = program.lir();
+        // the `+` in the loop
+        let body = (0..lir.stmts().len())
+            .find(|&ip| lir.location(ip) == (4..5))
+            .unwrap();
+
+        let mut vm = Vm::new(&program);
+        vm.add_breakpoint(body);
+        assert_eq!(vm.run_until(Until::Breakpoint), Stop::Breakpoint);
+        assert_eq!(vm.cell(1), 0);
+        assert_eq!(vm.run_until(Until::Breakpoint), Stop::Breakpoint);
+        assert_eq!(vm.cell(1), 1);
+
+        vm.remove_breakpoint(body);
+        assert_eq!(vm.run_until(Until::Breakpoint), Stop::End);
+        assert_eq!(vm.take_output(), [1, 2, 2]);
+    }
+
+    #[test]
+    fn inline_input() {
+        let options = CompileOptions {
+            dialect: "input".parse().unwrap(),
+            ..CompileOptions::default()
+        };
+        let program = Program::compile(",.,.!x", &options).unwrap();
+        let mut vm = Vm::new(&program);
+
+        assert_eq!(vm.run_until(Until::InputNeeded), Stop::InputNeeded);
+        assert_eq!(vm.take_output(), b"x");
+    }
+}
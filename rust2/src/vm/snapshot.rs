@@ -0,0 +1,284 @@
+//! saving the state of a [`Vm`] and resuming it later, possibly in another process
+//!
+//! The state is stored in a little binary format, numbers are LEB128 varints:
+//!
+//! ```text
+//! b"BFSTATE" version:u8 fingerprint:u64le
+//! steps:varint ip:varint ptr:varint
+//! tape_len:varint tape:[u8]                         up to the last cell that isn't zero
+//! input_len:varint input:[u8]                       fed, but not read yet
+//! output_len:varint output:[u8]                     written, but not taken yet
+//! procedure_count:varint (number:u8 start:varint)*  with the `pbrain` dialect
+//! call_depth:varint return_ip:varint*
+//! ```
+//!
+//! The [fingerprint](crate::lir::Lir::fingerprint) of the compiled code makes sure that a state
+//! is only resumed with the program that it was saved from. Breakpoints are not saved, they
+//! belong to the host.
+
+use std::{
+    fmt::{Display, Formatter},
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+use super::Vm;
+use crate::{
+    encoding::{self, write_bytes, write_varint},
+    lir::interpreter::MEM_SIZE,
+    Program,
+};
+
+const MAGIC: &[u8] = b"BFSTATE";
+const VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// the file is not a saved state, or a state of an unsupported version
+    Invalid(&'static str),
+    /// the state was saved from a program that was compiled to different code
+    OtherProgram,
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "Failed to read or write state: {err}"),
+            SnapshotError::Invalid(reason) => write!(f, "Invalid state: {reason}"),
+            SnapshotError::OtherProgram => write!(f, "The state was saved from another program"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl<'p> Vm<'p> {
+    /// Writes the state of the session to `out`, to be resumed with [`Vm::restore`]
+    pub fn save<W: Write>(&self, out: W) -> io::Result<()> {
+        let interpreter = &self.interpreter;
+        let mut out = BufWriter::new(out);
+
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        out.write_all(&interpreter.code().fingerprint().to_le_bytes())?;
+
+        write_varint(&mut out, self.steps)?;
+        write_varint(&mut out, interpreter.ip() as u64)?;
+        write_varint(&mut out, interpreter.ptr() as u64)?;
+
+        let tape_len = (0..MEM_SIZE)
+            .rposition(|idx| interpreter.cell(idx) != 0)
+            .map_or(0, |last| last + 1);
+        let tape = (0..tape_len)
+            .map(|idx| interpreter.cell(idx))
+            .collect::<Vec<_>>();
+        write_bytes(&mut out, &tape)?;
+
+        let (input_front, input_back) = interpreter.stdin().as_slices();
+        write_bytes(&mut out, &[input_front, input_back].concat())?;
+        write_bytes(&mut out, interpreter.stdout())?;
+
+        let procedures = interpreter.procedures().collect::<Vec<_>>();
+        write_varint(&mut out, procedures.len() as u64)?;
+        for (number, start) in procedures {
+            out.write_all(&[number])?;
+            write_varint(&mut out, start as u64)?;
+        }
+
+        let call_stack = interpreter.call_stack();
+        write_varint(&mut out, call_stack.len() as u64)?;
+        for &ip in call_stack {
+            write_varint(&mut out, ip as u64)?;
+        }
+
+        out.flush()
+    }
+
+    /// Resumes the session saved with [`Vm::save`]. `program` must be compiled from the same
+    /// source with the same options as the program of the saved session.
+    pub fn restore<R: Read>(program: &'p Program, input: R) -> Result<Self, SnapshotError> {
+        let mut input = BufReader::new(input);
+
+        let mut magic = [0; MAGIC.len()];
+        input
+            .read_exact(&mut magic)
+            .map_err(|_| SnapshotError::Invalid("not a saved state"))?;
+        if magic != MAGIC {
+            return Err(SnapshotError::Invalid("not a saved state"));
+        }
+        if read_u8(&mut input)? != VERSION {
+            return Err(SnapshotError::Invalid("unsupported version"));
+        }
+        let mut fingerprint = [0; 8];
+        input.read_exact(&mut fingerprint).map_err(invalid)?;
+        if u64::from_le_bytes(fingerprint) != program.lir().fingerprint() {
+            return Err(SnapshotError::OtherProgram);
+        }
+
+        let code_len = program.lir().stmts().len() as u64;
+        let read_ip = |input: &mut BufReader<R>| match read_varint(input)? {
+            ip if ip < code_len => Ok(ip as usize),
+            _ => Err(SnapshotError::Invalid("position outside of the code")),
+        };
+
+        let mut vm = Vm::new(program);
+        vm.steps = read_varint(&mut input)?;
+
+        let ip = read_ip(&mut input)?;
+        let ptr = match read_varint(&mut input)? {
+            ptr if ptr < MEM_SIZE as u64 => ptr as usize,
+            _ => return Err(SnapshotError::Invalid("pointer outside of the tape")),
+        };
+        let interpreter = &mut vm.interpreter;
+        interpreter.set_position(ip, ptr);
+
+        for (idx, value) in read_bytes(&mut input, MEM_SIZE)?.into_iter().enumerate() {
+            interpreter.set_cell(idx, value);
+        }
+
+        // replaces the inline input, which might have been read already
+        *interpreter.stdin_mut() = read_bytes(&mut input, usize::MAX)?.into();
+        *interpreter.stdout_mut() = read_bytes(&mut input, usize::MAX)?;
+
+        let procedure_count = read_varint(&mut input)?;
+        if procedure_count > 256 {
+            return Err(SnapshotError::Invalid("too many procedures"));
+        }
+        for _ in 0..procedure_count {
+            let number = read_u8(&mut input)?;
+            let start = read_ip(&mut input)?;
+            interpreter.define_procedure(number, start);
+        }
+
+        for _ in 0..read_varint(&mut input)? {
+            let ip = read_ip(&mut input)?;
+            interpreter.push_call(ip);
+        }
+
+        Ok(vm)
+    }
+}
+
+fn read_varint(input: &mut impl Read) -> Result<u64, SnapshotError> {
+    encoding::read_varint(input).map_err(invalid)
+}
+
+fn read_u8(input: &mut impl Read) -> Result<u8, SnapshotError> {
+    encoding::read_u8(input).map_err(invalid)
+}
+
+fn read_bytes(input: &mut impl Read, max_len: usize) -> Result<Vec<u8>, SnapshotError> {
+    encoding::read_bytes(input, max_len).map_err(invalid)
+}
+
+fn invalid(err: io::Error) -> SnapshotError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => SnapshotError::Invalid("unexpected end of state"),
+        io::ErrorKind::InvalidData => SnapshotError::Invalid("number out of range"),
+        _ => SnapshotError::Io(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SnapshotError;
+    use crate::{
+        vm::{Stop, Until},
+        CompileOptions, Program, Vm,
+    };
+
+    fn compile(src: &str, dialect: &str) -> Program {
+        let options = CompileOptions {
+            dialect: dialect.parse().unwrap(),
+            ..CompileOptions::default()
+        };
+        Program::compile(src, &options).unwrap()
+    }
+
+    fn save(vm: &Vm<'_>) -> Vec<u8> {
+        let mut state = Vec::new();
+        vm.save(&mut state).unwrap();
+        state
+    }
+
+    #[test]
+    fn resume_in_the_middle() {
+        let src = include_str!("../../benches/fizzbuzz.bf");
+        let program = compile(src, "classic");
+        let mut expected = Vec::new();
+        program.run(&Default::default(), [].as_slice(), &mut expected);
+
+        let mut vm = Vm::new(&program);
+        assert_eq!(vm.run_until(Until::Steps(10_000)), Stop::Steps);
+        let state = save(&vm);
+
+        // compiled again, like another process would
+        let program = compile(src, "classic");
+        let mut resumed = Vm::restore(&program, state.as_slice()).unwrap();
+        assert_eq!(resumed.steps(), 10_000);
+        assert_eq!((resumed.ip(), resumed.ptr()), (vm.ip(), vm.ptr()));
+
+        assert_eq!(resumed.run_until(Until::InputNeeded), Stop::End);
+        assert_eq!(resumed.take_output(), expected);
+    }
+
+    #[test]
+    fn pending_input_and_output() {
+        let program = compile(",[.,]", "classic");
+        let mut vm = Vm::new(&program);
+        vm.feed_input(b"abc");
+        assert_eq!(vm.run_until(Until::Output), Stop::Output);
+
+        let mut resumed = Vm::restore(&program, save(&vm).as_slice()).unwrap();
+        resumed.feed_input(b"\0");
+        assert_eq!(resumed.run_until(Until::InputNeeded), Stop::End);
+        assert_eq!(resumed.take_output(), b"abc");
+    }
+
+    #[test]
+    fn procedures() {
+        // defines procedure 1, which prints the next cell, and calls it
+        let program = compile("+(>.<)>+++<:", "pbrain");
+        let lir = program.lir();
+        let print = (0..lir.stmts().len())
+            .find(|&ip| lir.location(ip) == (3..4))
+            .unwrap();
+
+        let mut vm = Vm::new(&program);
+        vm.add_breakpoint(print);
+        assert_eq!(vm.run_until(Until::Breakpoint), Stop::Breakpoint);
+
+        let mut resumed = Vm::restore(&program, save(&vm).as_slice()).unwrap();
+        assert_eq!(resumed.run_until(Until::InputNeeded), Stop::End);
+        assert_eq!(resumed.take_output(), [3]);
+    }
+
+    #[test]
+    fn other_program() {
+        let program = compile("+.", "classic");
+        let state = save(&Vm::new(&program));
+
+        let other = compile("-.", "classic");
+        let result = Vm::restore(&other, state.as_slice());
+        assert!(matches!(result, Err(SnapshotError::OtherProgram)));
+    }
+
+    #[test]
+    fn invalid_state() {
+        let program = compile("+.", "classic");
+        let mut state = save(&Vm::new(&program));
+
+        let result = Vm::restore(&program, b"+[-]".as_slice());
+        assert!(matches!(result, Err(SnapshotError::Invalid(_))));
+
+        state.pop();
+        let result = Vm::restore(&program, state.as_slice());
+        assert!(matches!(result, Err(SnapshotError::Invalid(_))));
+    }
+}
@@ -0,0 +1,188 @@
+//! watchpoints on tape cells
+//!
+//! Watchpoints see the writes of the optimized code, where a single statement can stand for a
+//! whole run of `+` or `-`. A `cell=value` watchpoint is also hit by the values that such a
+//! statement passes through, as if the statements it was merged from ran one by one. A cell that
+//! is cleared and set to a new value counts down to zero first, and then takes the shorter way to
+//! the new value.
+
+use std::{io::Write, str::FromStr};
+
+use crate::lir::{
+    interpreter::{Watcher, MEM_SIZE},
+    Lir, Stmt,
+};
+
+/// Triggers whenever `cell` is written to, or if there is a `value`, whenever `cell` changes to
+/// `value`. Parsed from `cell` or `cell=value`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub cell: usize,
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    /// Whether `stmt`, with the pointer at `ptr`, hits the watchpoint by changing the cell at
+    /// `idx` from `old` to `new`
+    pub fn is_hit(&self, stmt: Stmt, ptr: usize, idx: usize, old: u8, new: u8) -> bool {
+        if idx != self.cell {
+            return false;
+        }
+        let Some(value) = self.value else {
+            return true;
+        };
+
+        // whether counting from `from` to `to` one by one reaches `value`, not counting `from`
+        let up = |from: u8, to: u8| (1..=to.wrapping_sub(from)).contains(&value.wrapping_sub(from));
+        let down =
+            |from: u8, to: u8| (1..=from.wrapping_sub(to)).contains(&from.wrapping_sub(value));
+
+        match stmt {
+            Stmt::Add(_) | Stmt::AddOffset { .. } => up(old, new),
+            Stmt::Sub(_) | Stmt::SubOffset { .. } => down(old, new),
+            // the loop this came from moves the cell under the pointer to the other one, one by one
+            Stmt::MoveAddTo { .. } if idx == ptr => down(old, new),
+            Stmt::MoveAddTo { .. } => up(old, new),
+            Stmt::SetN(_) if new < 128 => down(old, 0) || up(0, new),
+            Stmt::SetN(_) => down(old, 0) || down(0, new),
+            _ => new == value && old != value,
+        }
+    }
+}
+
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cell, value) = match s.split_once('=') {
+            Some((cell, value)) => (cell, Some(value)),
+            None => (s, None),
+        };
+
+        let cell = cell
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid cell index: '{cell}'"))?;
+        if cell >= MEM_SIZE {
+            return Err(format!(
+                "Cell index {cell} is beyond the {MEM_SIZE} cells of the tape"
+            ));
+        }
+        let value = value
+            .map(|value| value.trim().parse())
+            .transpose()
+            .map_err(|_| format!("Invalid cell value: '{}'", value.unwrap_or_default()))?;
+
+        Ok(Self { cell, value })
+    }
+}
+
+/// Logs every hit watchpoint to `out`
+pub(crate) struct WatchLogger<'a, W> {
+    watchpoints: &'a [Watchpoint],
+    lir: &'a Lir,
+    out: W,
+}
+
+impl<'a, W: Write> WatchLogger<'a, W> {
+    pub(crate) fn new(watchpoints: &'a [Watchpoint], lir: &'a Lir, out: W) -> Self {
+        Self {
+            watchpoints,
+            lir,
+            out,
+        }
+    }
+}
+
+impl<W: Write> Watcher for WatchLogger<'_, W> {
+    fn write(&mut self, ip: usize, ptr: usize, idx: usize, old: u8, new: u8) {
+        let stmt = self.lir.stmts()[ip];
+        if self
+            .watchpoints
+            .iter()
+            .any(|w| w.is_hit(stmt, ptr, idx, old, new))
+        {
+            writeln!(
+                self.out,
+                "watchpoint: cell {idx} changed from {old} to {new} by {stmt:?} at {:?}",
+                self.lir.debug()[ip]
+            )
+            .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WatchLogger, Watchpoint};
+    use crate::{
+        lir::{self, interpreter::MEM_SIZE, Stmt},
+        CompileOptions, Program,
+    };
+
+    fn hits(src: &str, watchpoint: &str) -> String {
+        let program = Program::compile(src, &CompileOptions::default()).unwrap();
+        let watch = [watchpoint.parse().unwrap()];
+        let mut log = Vec::new();
+        let logger = WatchLogger::new(&watch, program.lir(), &mut log);
+        lir::interpreter::run_watched(
+            program.lir(),
+            std::io::sink(),
+            std::io::empty(),
+            |_| {},
+            logger,
+        );
+        String::from_utf8(log).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "17".parse(),
+            Ok(Watchpoint {
+                cell: 17,
+                value: None
+            })
+        );
+        assert_eq!(
+            "3=255".parse(),
+            Ok(Watchpoint {
+                cell: 3,
+                value: Some(255)
+            })
+        );
+        assert!("3=256".parse::<Watchpoint>().is_err());
+        assert!("x".parse::<Watchpoint>().is_err());
+        assert!(MEM_SIZE.to_string().parse::<Watchpoint>().is_err());
+    }
+
+    #[test]
+    fn reaching_a_value() {
+        let watchpoint = Watchpoint {
+            cell: 2,
+            value: Some(5),
+        };
+        assert!(watchpoint.is_hit(Stmt::In, 0, 2, 4, 5));
+        assert!(!watchpoint.is_hit(Stmt::In, 0, 2, 5, 5));
+        assert!(!watchpoint.is_hit(Stmt::In, 0, 1, 4, 5));
+
+        // the cell under the pointer counts down, the other one up
+        let move_add_to = Stmt::MoveAddTo { offset: -2 };
+        assert!(watchpoint.is_hit(move_add_to, 2, 2, 9, 0));
+        assert!(!watchpoint.is_hit(move_add_to, 2, 2, 4, 0));
+        assert!(watchpoint.is_hit(move_add_to, 4, 2, 250, 7));
+        assert!(!watchpoint.is_hit(move_add_to, 4, 2, 6, 9));
+    }
+
+    #[test]
+    fn merged_writes() {
+        // `+++` is a single write from 0 to 3, which passes 2 on the way
+        assert!(hits("+++", "0=2").contains("changed from 0 to 3"));
+        assert!(hits("---", "0=254").contains("changed from 0 to 253"));
+        assert_eq!(hits("+++", "0=4"), "");
+        // and so is clearing the cell and counting to a new value
+        assert!(hits("+++[-]++", "0=0").contains("changed from 3 to 2"));
+        assert!(hits("+++[-]--", "0=255").contains("changed from 3 to 254"));
+        assert_eq!(hits("+++[-]++", "0=255"), "");
+    }
+}